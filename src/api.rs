@@ -1,43 +1,300 @@
-use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    sync::Arc,
+};
 
 use acdc::{Attestation, Authored, Hashed, PubKey, Signed};
-use keri::prefix::Prefix;
-use serde::Deserialize;
-use tokio::sync::RwLock;
+use futures::{SinkExt, StreamExt};
+use keri::{
+    event::sections::KeyConfig,
+    prefix::{Prefix, SelfAddressingPrefix},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, RwLock};
 use warp::{Filter, Reply};
 
-use crate::{controller::Controller, WitnessConfig};
+use crate::{
+    controller::{is_threshold_met, Controller, ControllerError, ThresholdConfig},
+    metrics::Metrics,
+    schema,
+    storage::{self, AttestationStore, SignedAttestation},
+    tel::AttestationStatus,
+    Url, WitnessConfig,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ApiError {
+    Unauthorized,
     SigningError,
     InvalidAttestation,
+    MissingField(&'static str),
     VerificationFailed,
     // InvalidIssuer,
     UnknownIssuer,
+    IssuerNotAllowed(String),
+    NotFound,
+    Conflict,
+    AlreadyCreated(String),
+    SchemaViolation(Vec<String>),
+    MissingEdge(String),
+    CyclicChain(String),
+    InvalidRequest(String),
+    MethodNotAllowed,
+    ReadOnly,
+    PayloadTooLarge,
+    Controller(ControllerError),
     SomeError(String),
 }
 
+impl ApiError {
+    fn name(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::SigningError => "SigningError",
+            ApiError::InvalidAttestation | ApiError::MissingField(_) => "InvalidAttestation",
+            ApiError::VerificationFailed => "VerificationFailed",
+            ApiError::UnknownIssuer => "UnknownIssuer",
+            ApiError::IssuerNotAllowed(_) => "IssuerNotAllowed",
+            ApiError::NotFound => "NotFound",
+            ApiError::Conflict => "Conflict",
+            ApiError::AlreadyCreated(_) => "AlreadyCreated",
+            ApiError::SchemaViolation(_) => "SchemaViolation",
+            ApiError::MissingEdge(_) => "MissingEdge",
+            ApiError::CyclicChain(_) => "CyclicChain",
+            ApiError::InvalidRequest(_) => "InvalidRequest",
+            ApiError::MethodNotAllowed => "MethodNotAllowed",
+            ApiError::ReadOnly => "ReadOnly",
+            ApiError::PayloadTooLarge => "PayloadTooLarge",
+            ApiError::Controller(e) => match e {
+                ControllerError::MissingIp(_) => "MissingIp",
+                ControllerError::WitnessResolution(_) => "WitnessResolution",
+                ControllerError::PublishFailed { .. } => "PublishFailed",
+                ControllerError::ImproperThreshold { .. } => "ImproperThreshold",
+                ControllerError::ThresholdExceedsWitnesses { .. } => "ThresholdExceedsWitnesses",
+                ControllerError::ZeroThreshold(_) => "ZeroThreshold",
+                ControllerError::ImproperKeyThreshold { .. } => "ImproperKeyThreshold",
+                ControllerError::ThresholdNotMet => "ThresholdNotMet",
+                ControllerError::UnknownSignature(_) => "UnknownSignature",
+                ControllerError::UnknownIssuer(_) => "UnknownIssuer",
+                ControllerError::NotIncepted => "NotIncepted",
+                ControllerError::Parse(_) => "ParseError",
+                ControllerError::AlreadyRevoked(_) => "AlreadyRevoked",
+                ControllerError::DatabaseInUse(_) => "DatabaseInUse",
+                ControllerError::UnexpectedSn { .. } => "UnexpectedSn",
+                ControllerError::DelegationRejected(_) => "DelegationRejected",
+                ControllerError::DelegationTimeout(_) => "DelegationTimeout",
+            },
+            ApiError::SomeError(_) => "SomeError",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::Unauthorized => "Missing or invalid API token".into(),
+            ApiError::SigningError => "Signing attestation failed".into(),
+            ApiError::InvalidAttestation => "Attestation can't be parsed".into(),
+            ApiError::MissingField(field) => format!("Attestation is missing field {}", field),
+            ApiError::VerificationFailed => "Attestation signature verification failed".into(),
+            ApiError::UnknownIssuer => "Can't find issuer's keys".into(),
+            ApiError::IssuerNotAllowed(issuer) => {
+                format!("Attestations issued by {} aren't accepted", issuer)
+            }
+            ApiError::NotFound => "Not found".into(),
+            ApiError::Conflict => {
+                "Different attestation with the same hash is already stored".into()
+            }
+            ApiError::AlreadyCreated(hash) => format!("Attestation {} is already created", hash),
+            ApiError::SchemaViolation(violations) => violations.join("; "),
+            ApiError::MissingEdge(hash) => format!("Chained attestation {} isn't known", hash),
+            ApiError::CyclicChain(hash) => {
+                format!("Attestation {} chains back to itself", hash)
+            }
+            ApiError::InvalidRequest(msg) => msg.clone(),
+            ApiError::MethodNotAllowed => "Method not allowed".into(),
+            ApiError::ReadOnly => "Daemon is read-only, it can't sign or change stored data".into(),
+            ApiError::PayloadTooLarge => "Request body is too large".into(),
+            ApiError::Controller(e) => e.to_string(),
+            ApiError::SomeError(msg) => msg.clone(),
+        }
+    }
+
+    fn status(&self) -> warp::http::StatusCode {
+        use warp::http::StatusCode;
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::IssuerNotAllowed(_) => StatusCode::FORBIDDEN,
+            ApiError::InvalidAttestation
+            | ApiError::MissingField(_)
+            | ApiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::MethodNotAllowed | ApiError::ReadOnly => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnknownIssuer | ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Conflict | ApiError::AlreadyCreated(_) => StatusCode::CONFLICT,
+            ApiError::VerificationFailed
+            | ApiError::SchemaViolation(_)
+            | ApiError::MissingEdge(_)
+            | ApiError::CyclicChain(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Controller(e) => match e {
+                ControllerError::MissingIp(_)
+                | ControllerError::WitnessResolution(_)
+                | ControllerError::PublishFailed { .. } => StatusCode::BAD_GATEWAY,
+                ControllerError::DelegationTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                ControllerError::DelegationRejected(_) => StatusCode::FORBIDDEN,
+                ControllerError::ImproperThreshold { .. }
+                | ControllerError::ThresholdExceedsWitnesses { .. }
+                | ControllerError::ZeroThreshold(_)
+                | ControllerError::ImproperKeyThreshold { .. } => StatusCode::BAD_REQUEST,
+                ControllerError::ThresholdNotMet
+                | ControllerError::UnknownSignature(_)
+                | ControllerError::Parse(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                ControllerError::UnknownIssuer(_) => StatusCode::NOT_FOUND,
+                ControllerError::NotIncepted
+                | ControllerError::AlreadyRevoked(_)
+                | ControllerError::UnexpectedSn { .. } => StatusCode::CONFLICT,
+                ControllerError::DatabaseInUse(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ApiError::SigningError | ApiError::SomeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<ControllerError>() {
+            Some(ControllerError::UnknownIssuer(_)) => ApiError::UnknownIssuer,
+            Some(controller_error) => ApiError::Controller(controller_error.clone()),
+            None => ApiError::SomeError(e.to_string()),
+        }
+    }
+}
+
 impl warp::Reply for ApiError {
     fn into_response(self) -> warp::reply::Response {
-        let mut resp = warp::reply::Response::new(format!("{:?}", self).into());
-        *resp.status_mut() = warp::hyper::StatusCode::INTERNAL_SERVER_ERROR;
-        resp
+        let body = serde_json::json!({
+            "error": self.name(),
+            "message": self.message(),
+        });
+        warp::reply::with_status(warp::reply::json(&body), self.status()).into_response()
     }
 }
 
 impl warp::reject::Reject for ApiError {}
 
-pub(crate) type AttestationDB = Arc<RwLock<HashMap<String, Signed<Hashed<Attestation>>>>>;
+const CESR_CONTENT_TYPE: &str = "application/json+cesr";
+
+pub(crate) type AttestationDB = Arc<RwLock<AttestationStore>>;
+
+/// Issuers whose attestations are accepted. Any issuer is accepted if it's
+/// empty.
+type IssuerAllowlist = Arc<HashSet<String>>;
+
+/// Identifier served by the daemon, with its own controller and attestation
+/// store.
+pub(crate) struct ServedIdentifier {
+    pub prefix: String,
+    pub controller: Arc<RwLock<Controller>>,
+    pub attest_db: AttestationDB,
+}
 
+/// Serves API of every identifier under `/{prefix}/`. The first identifier is
+/// served without the prefix too, the same way it was before the daemon
+/// could serve more of them. With `read_only` set, routes which sign or change
+/// the KEL answer `405 method not allowed`.
 pub(crate) fn setup_routes(
+    identifiers: &[ServedIdentifier],
+    api_token: Option<String>,
+    issuer_allowlist: HashSet<String>,
+    body_limit: u64,
+    read_only: bool,
+    cors: warp::cors::Builder,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let issuer_allowlist = Arc::new(issuer_allowlist);
+    let version_route = warp::path("version")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(version)
+        .map(Reply::into_response);
+    let primary = identifiers
+        .first()
+        .expect("daemon serves at least one identifier");
+    let routes = identifier_routes(
+        primary.controller.clone(),
+        primary.attest_db.clone(),
+        api_token.clone(),
+        issuer_allowlist.clone(),
+        body_limit,
+        read_only,
+    )
+    .map(Reply::into_response)
+    .boxed();
+    identifiers
+        .iter()
+        .fold(routes, |routes, identifier| {
+            let prefixed = warp::path(identifier.prefix.clone())
+                .and(identifier_routes(
+                    identifier.controller.clone(),
+                    identifier.attest_db.clone(),
+                    api_token.clone(),
+                    issuer_allowlist.clone(),
+                    body_limit,
+                    read_only,
+                ))
+                .map(Reply::into_response);
+            routes.or(prefixed).unify().boxed()
+        })
+        .or(version_route)
+        .unify()
+        .recover(handle_rejection)
+        .with(cors)
+        .with(warp::log(module_path!()))
+        // Span of every request, calls made while handling it are nested in it.
+        .with(warp::trace::request())
+}
+
+/// Reports the daemon version, with the git commit and time it was built
+/// from, see `build.rs`.
+fn version() -> impl Reply {
+    use chrono::TimeZone;
+
+    #[derive(Serialize)]
+    struct Version {
+        version: &'static str,
+        git_hash: &'static str,
+        build_timestamp: String,
+    }
+
+    let build_timestamp = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::Utc.timestamp_opt(secs, 0).single())
+        .map(|built_at| built_at.to_rfc3339())
+        .unwrap_or_default();
+    warp::reply::json(&Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        build_timestamp,
+    })
+}
+
+/// Routes of a single identifier.
+fn identifier_routes(
     controller: Arc<RwLock<Controller>>,
     // dht_node: Arc<RwLock<Node>>,
     attest_db: AttestationDB,
-    cors: warp::cors::Builder,
+    api_token: Option<String>,
+    issuer_allowlist: IssuerAllowlist,
+    body_limit: u64,
+    read_only: bool,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let authorized = authorized(api_token);
+    let writable = writable(read_only);
+
     let attest_list_route = warp::path("attestations")
+        .and(warp::path::end())
         .and(warp::get())
+        .and(warp::query::<ListParams>())
+        .and(warp::header::optional::<String>("accept"))
         .and(warp::any().map({
             let attest_db = attest_db.clone();
             move || attest_db.clone()
@@ -45,10 +302,123 @@ pub(crate) fn setup_routes(
         .then(attest_list)
         .map(handle_result);
 
-    let attest_create_route = warp::path("attestations")
-        .and(warp::path("create"))
+    let attest_subscribe_route = warp::path!("attestations" / "subscribe")
+        .and(warp::ws())
+        .and(warp::query::<SubscribeParams>())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .map(
+            |ws: warp::ws::Ws, params: SubscribeParams, attest_db: AttestationDB| {
+                ws.on_upgrade(move |socket| attest_subscribe(socket, params, attest_db))
+            },
+        );
+
+    let attest_export_route = warp::path!("attestations" / "export")
+        .and(warp::get())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .then(attest_export)
+        .map(handle_result);
+
+    let attest_import_route = warp::path!("attestations" / "import")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .and(warp::any().map({
+            let issuer_allowlist = issuer_allowlist.clone();
+            move || issuer_allowlist.clone()
+        }))
+        .then(attest_import)
+        .map(handle_result);
+
+    let attest_get_route = warp::path!("attestations" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .then(attest_get)
+        .map(handle_result);
+
+    let attest_head_route = warp::path!("attestations" / String)
+        .and(warp::head())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .then(attest_head);
+
+    let attest_delete_route = warp::path!("attestations" / String)
+        .and(warp::delete())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .then(attest_delete)
+        .map(handle_result);
+
+    let attest_status_route = warp::path!("attestations" / String / "status")
+        .and(warp::get())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(attest_status)
+        .map(handle_result);
+
+    let attest_chain_route = warp::path!("attestations" / String / "chain")
+        .and(warp::get())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .then(attest_chain)
+        .map(handle_result);
+
+    let attest_revoke_route = warp::path!("attestations" / String / "revoke")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(attest_revoke)
+        .map(handle_result);
+
+    let attest_create_route = warp::path!("attestations" / "create")
         .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
         .and(warp::body::json())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("idempotency-key"))
         .and(warp::any().map({
             let attest_db = attest_db.clone();
             move || attest_db.clone()
@@ -60,8 +430,28 @@ pub(crate) fn setup_routes(
         .then(attest_create)
         .map(handle_result);
 
+    let attest_create_batch_route = warp::path!("attestations" / "create_batch")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(attest_create_batch)
+        .map(handle_result);
+
     let attest_receive_route = warp::path("attestations")
+        .and(warp::path::end())
         .and(warp::post())
+        .and(writable.clone())
+        .and(warp::body::content_length_limit(body_limit))
         .and(warp::body::bytes())
         .and(warp::any().map({
             let attest_db = attest_db;
@@ -71,143 +461,1504 @@ pub(crate) fn setup_routes(
             let controller = controller.clone();
             move || controller.clone()
         }))
+        .and(warp::any().map(move || issuer_allowlist.clone()))
         .then(attest_receive)
         .map(handle_result);
 
-    let rotation_route = warp::path("rotate")
+    let attest_verify_route = warp::path("verify")
+        .and(warp::path::end())
         .and(warp::post())
+        .and(warp::body::content_length_limit(body_limit))
         .and(warp::body::bytes())
         .and(warp::any().map({
-            let controller = controller;
+            let controller = controller.clone();
             move || controller.clone()
         }))
-        .then(rotate)
+        .then(attest_verify)
         .map(handle_result);
 
-    attest_list_route
-        .or(attest_create_route)
-        .or(attest_receive_route)
-        .or(rotation_route)
-        .with(cors)
-        .with(warp::log(module_path!()))
-}
+    let attest_verify_chain_route = warp::path!("attestations" / "verify_chain")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(attest_verify_chain)
+        .map(handle_result);
 
-fn handle_result(result: Result<impl warp::Reply, impl warp::Reply>) -> impl warp::Reply {
-    match result {
-        Ok(val) => val.into_response(),
-        Err(err) => err.into_response(),
-    }
-}
+    let kel_route = warp::path("kel")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(get_kel)
+        .map(handle_result);
 
-async fn attest_list(attest_db: AttestationDB) -> Result<warp::reply::Json, Infallible> {
-    let attest_db = attest_db.read().await;
-    let attests = attest_db
-        .iter()
-        .map(|(_id, attest)| &attest.data)
-        .collect::<Vec<_>>();
-    Ok(warp::reply::json(&attests))
-}
+    let kel_events_route = warp::path("kel")
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(get_kel_events)
+        .map(handle_result);
 
-async fn attest_create(
-    attest: Attestation,
-    attest_db: AttestationDB,
-    controller: Arc<RwLock<Controller>>,
-) -> Result<impl Reply, ApiError> {
-    // Hash
-    let attest = Hashed::new(Attestation {
-        issuer: controller.read().await.get_prefix().to_str(),
-        ..attest
-    });
-    let attest_hash = attest.get_hash().to_string();
-    log::info!("Created attestation {:?}", attest_hash);
+    let state_route = warp::path("state")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(get_state)
+        .map(handle_result);
 
-    // Sign
-    let sig = {
-        let priv_key = &*controller.read().await;
-        let msg = &Signed::get_json_bytes(&attest);
-        priv_key
-            .sign(msg)
-            .map_err(|e| ApiError::SomeError(e.to_string()))?
-    };
-    let attest =
-        Signed::new_with_keri_signatures(attest, &[sig]).map_err(|_| (ApiError::SigningError))?;
+    let receipts_route = warp::path!("receipts" / u64)
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(get_event_receipts)
+        .map(handle_result);
 
-    // Save
-    {
-        let mut attest_db = attest_db.write().await;
-        attest_db.insert(attest_hash.clone(), attest.clone());
-    }
+    let resolve_route = warp::path!("resolve" / String)
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(resolve_state)
+        .map(handle_result);
 
-    Ok(warp::reply::with_header(
-        attest.to_signed_json(),
-        "Content-Type",
-        "text/plain",
-    ))
-}
+    let witnesses_route = warp::path("witnesses")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(witnesses)
+        .map(handle_result);
 
-async fn attest_receive(
-    attest: warp::hyper::body::Bytes,
-    attest_db: AttestationDB,
-    controller: Arc<RwLock<Controller>>,
-    // dht_node: Arc<RwLock<Node>>,
-) -> Result<Box<dyn Reply>, ApiError> {
-    // Parse
-    let attest = std::str::from_utf8(&attest).map_err(|_| ApiError::InvalidAttestation)?;
-    let attest = Signed::<Hashed<Attestation>>::from_signed_json(attest)
-        .map_err(|_| ApiError::InvalidAttestation)?;
-    let attest_issuer = attest.data.get_author_id();
-    let attest_hash = attest.data.get_hash().to_string();
-    log::info!(
-        "Received attestation {:?} by {:?}",
-        attest_hash,
-        attest_issuer
-    );
+    let health_route = warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(health)
+        .map(handle_result);
 
-    // Verify
-    let result = {
-        let key_config = controller
-            .read()
-            .await
-            .get_public_keys(&attest_issuer.parse().unwrap_or_default())
-            .await
-            .map_err(|_e| ApiError::UnknownIssuer)?
-            .ok_or(ApiError::UnknownIssuer)?;
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(get_metrics)
+        .map(handle_result);
 
-        let keys = {
-            let mut keys = HashMap::new();
-            keys.insert(attest_issuer.to_owned(), PubKey::KeriKeys(key_config));
-            keys
-        };
-        attest
-            .verify(&keys)
-            .map_err(|_| ApiError::VerificationFailed)
-    };
+    let interaction_route = warp::path("interact")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(interact)
+        .map(handle_result);
 
-    // Save
-    match result {
-        Ok(_) => {
-            let mut attest_db = attest_db.write().await;
-            attest_db.insert(attest_hash, attest.clone());
-            Ok(Box::new(warp::reply::json(&attest.data)))
-        }
-        Err(_) => Ok(Box::new(warp::reply::with_status(
+    let delegate_route = warp::path("delegate")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(delegate)
+        .map(handle_result);
+
+    let kel_import_route = warp::path!("kel" / "import")
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(import_kel)
+        .map(handle_result);
+
+    let get_oobi_route = warp::path("oobi")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(get_oobi)
+        .map(handle_result);
+
+    let oobi_route = warp::path("oobi")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(resolve_oobi)
+        .map(handle_result);
+
+    let schema_register_route = warp::path("schemas")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let attest_db = attest_db.clone();
+            move || attest_db.clone()
+        }))
+        .then(schema_register)
+        .map(handle_result);
+
+    let sign_route = warp::path("sign")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(writable.clone())
+        .and(authorized.clone())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::query::<SignParams>())
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let controller = controller.clone();
+            move || controller.clone()
+        }))
+        .then(sign)
+        .map(handle_result);
+
+    let rotation_route = warp::path("rotate")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(writable)
+        .and(authorized)
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::bytes())
+        .and(warp::any().map({
+            let controller = controller;
+            move || controller.clone()
+        }))
+        .then(rotate)
+        .map(handle_result);
+
+    attest_list_route
+        .or(attest_subscribe_route)
+        .or(attest_export_route)
+        .or(attest_get_route)
+        .or(attest_head_route)
+        .or(attest_delete_route)
+        .or(attest_status_route)
+        .or(attest_chain_route)
+        .or(attest_revoke_route)
+        .or(attest_create_route)
+        .or(attest_create_batch_route)
+        .or(attest_import_route)
+        .or(attest_receive_route)
+        .or(attest_verify_route)
+        .or(attest_verify_chain_route)
+        .or(kel_route)
+        .or(kel_events_route)
+        .or(state_route)
+        .or(receipts_route)
+        .or(resolve_route)
+        .or(witnesses_route)
+        .or(health_route)
+        .or(metrics_route)
+        .or(interaction_route)
+        .or(delegate_route)
+        .or(kel_import_route)
+        .or(get_oobi_route)
+        .or(oobi_route)
+        .or(schema_register_route)
+        .or(sign_route)
+        .or(rotation_route)
+}
+
+/// Rejects requests without `Authorization: Bearer <api_token>` header. All
+/// requests pass if no token is configured.
+fn authorized(
+    api_token: Option<String>,
+) -> impl warp::Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let api_token = Arc::new(api_token);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let api_token = api_token.clone();
+            async move {
+                let api_token = match api_token.as_ref() {
+                    Some(api_token) => api_token,
+                    None => return Ok(()),
+                };
+                match header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                    Some(token) if constant_time_eq(token.as_bytes(), api_token.as_bytes()) => {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(ApiError::Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Rejects requests of routes which sign or change the KEL when the daemon
+/// is read-only.
+fn writable(read_only: bool) -> impl warp::Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if read_only {
+                Err(warp::reject::custom(ApiError::ReadOnly))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// Compares secrets in time independent of the position of the first
+/// mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Turns every rejection into the JSON error response, including ones of
+/// warp's own filters.
+async fn handle_rejection(rejection: warp::Rejection) -> Result<ApiError, Infallible> {
+    use warp::reject;
+
+    if let Some(e) = rejection.find::<ApiError>() {
+        return Ok(e.clone());
+    }
+    let error = if rejection.is_not_found() {
+        ApiError::NotFound
+    } else if rejection.find::<reject::MethodNotAllowed>().is_some() {
+        ApiError::MethodNotAllowed
+    } else if rejection.find::<reject::PayloadTooLarge>().is_some() {
+        ApiError::PayloadTooLarge
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::InvalidRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+        ApiError::InvalidRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidHeader>() {
+        ApiError::InvalidRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MissingHeader>() {
+        ApiError::InvalidRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+        ApiError::InvalidRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<reject::UnsupportedMediaType>() {
+        ApiError::InvalidRequest(e.to_string())
+    } else {
+        log::warn!("Unhandled rejection: {:?}", rejection);
+        ApiError::SomeError("Request can't be handled".into())
+    };
+    Ok(error)
+}
+
+fn handle_result(result: Result<impl warp::Reply, impl warp::Reply>) -> impl warp::Reply {
+    match result {
+        Ok(val) => val.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Number of attestations returned by the list route when no limit is given.
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct ListParams {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    issuer: Option<String>,
+    recipient: Option<String>,
+    /// `hash` (default), `created_asc` or `created_desc`.
+    sort: Option<String>,
+    /// List JSON attestations together with the time they were stored.
+    with_metadata: Option<bool>,
+}
+
+async fn attest_list(
+    params: ListParams,
+    accept: Option<String>,
+    attest_db: AttestationDB,
+) -> Result<warp::reply::Response, Infallible> {
+    let attest_db = attest_db.read().await;
+    let mut attests = attest_db
+        .iter()
+        .filter(|(_id, attest)| match &params.issuer {
+            Some(issuer) => attest.data.get_author_id() == issuer.as_str(),
+            None => true,
+        })
+        .filter(|(_id, attest)| match &params.recipient {
+            Some(recipient) => storage::recipient(attest).as_ref() == Some(recipient),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    // Keep the order stable between pages.
+    attests.sort_by(|(a, _), (b, _)| a.cmp(b));
+    match params.sort.as_deref() {
+        None | Some("hash") => (),
+        // Stable sort keeps attestations stored at the same time, or without
+        // timestamp, in hash order.
+        Some("created_asc") => attests.sort_by_cached_key(|(hash, _)| attest_db.stored_at(hash)),
+        Some("created_desc") => {
+            attests.sort_by_cached_key(|(hash, _)| std::cmp::Reverse(attest_db.stored_at(hash)))
+        }
+        Some(order) => {
+            return Ok(
+                ApiError::InvalidRequest(format!("Unknown sort order {:?}", order)).into_response(),
+            )
+        }
+    }
+    let attests = attests
+        .into_iter()
+        .skip(params.offset.unwrap_or_default())
+        .take(params.limit.unwrap_or(DEFAULT_PAGE_SIZE));
+    Ok(match AttestationFormat::from_accept(accept) {
+        AttestationFormat::Json if params.with_metadata.unwrap_or(false) => warp::reply::json(
+            &attests
+                .map(|(hash, attest)| {
+                    serde_json::json!({
+                        "attestation": attest.data,
+                        "stored_at": attest_db.stored_at(&hash).map(|t| t.to_rfc3339()),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        AttestationFormat::Json => {
+            warp::reply::json(&attests.map(|(_id, attest)| attest.data).collect::<Vec<_>>())
+                .into_response()
+        }
+        AttestationFormat::Cesr => AttestationFormat::Cesr
+            .reply(attests.map(|(_id, attest)| to_cesr(&attest)).collect())
+            .into_response(),
+    })
+}
+
+/// Attestation representation picked by the `Accept` header. Signed JSON is
+/// used unless CESR is asked for.
+#[derive(Clone, Copy)]
+enum AttestationFormat {
+    Json,
+    Cesr,
+}
+
+impl AttestationFormat {
+    fn from_accept(accept: Option<String>) -> Self {
+        accept
+            .iter()
+            .flat_map(|accept| accept.split(','))
+            .filter_map(|media_type| match media_type.split(';').next()?.trim() {
+                "application/json" => Some(AttestationFormat::Json),
+                "application/cesr" => Some(AttestationFormat::Cesr),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(AttestationFormat::Json)
+    }
+
+    fn render(self, attest: &SignedAttestation) -> String {
+        match self {
+            AttestationFormat::Json => attest.to_signed_json(),
+            AttestationFormat::Cesr => to_cesr(attest),
+        }
+    }
+
+    fn reply(self, body: String) -> impl Reply {
+        let content_type = match self {
+            AttestationFormat::Json => "application/json",
+            AttestationFormat::Cesr => "application/cesr",
+        };
+        warp::reply::with_header(body, "Content-Type", content_type)
+    }
+}
+
+/// Returns attestation as CESR stream, its JSON body followed by attached
+/// KERI signatures.
+fn to_cesr(attest: &SignedAttestation) -> String {
+    let signed_attest = attest.to_signed_json();
+    match split_signed_json(&signed_attest) {
+        Ok((body, signatures)) => format!("{}{}", body, signatures),
+        Err(_) => signed_attest,
+    }
+}
+
+#[derive(Deserialize)]
+struct SubscribeParams {
+    from_hash: Option<String>,
+}
+
+/// Sends every newly stored attestation to the websocket. With `from_hash`,
+/// stored attestations starting from that hash, ordered by hash like in the
+/// list route, are sent first.
+async fn attest_subscribe(
+    socket: warp::ws::WebSocket,
+    params: SubscribeParams,
+    attest_db: AttestationDB,
+) {
+    let (mut socket, _) = socket.split();
+    let (mut stored, replay) = {
+        let attest_db = attest_db.read().await;
+        // Subscribe before reading stored ones, so nothing is missed.
+        let stored = attest_db.subscribe();
+        let mut replay = match &params.from_hash {
+            Some(from_hash) => attest_db
+                .iter()
+                .filter(|(hash, _)| hash.as_str() >= from_hash.as_str())
+                .map(|(hash, attest)| (hash, attest.to_signed_json()))
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        replay.sort_by(|(a, _), (b, _)| a.cmp(b));
+        (stored, replay)
+    };
+
+    for (_, attest) in replay {
+        if socket.send(warp::ws::Message::text(attest)).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        match stored.recv().await {
+            Ok(attest) => {
+                if socket.send(warp::ws::Message::text(attest)).await.is_err() {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Subscriber lagged behind, skipped {} attestations", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(hash = %hash))]
+async fn attest_get(
+    hash: String,
+    accept: Option<String>,
+    attest_db: AttestationDB,
+) -> Result<impl Reply, ApiError> {
+    let attest_db = attest_db.read().await;
+    let attest = attest_db.get(&hash).ok_or(ApiError::NotFound)?;
+    let format = AttestationFormat::from_accept(accept);
+    let mut response = format.reply(format.render(&attest)).into_response();
+    if let Some(stored_at) = attest_db.stored_at(&hash) {
+        if let Ok(value) = stored_at
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+            .parse()
+        {
+            response.headers_mut().insert("Last-Modified", value);
+        }
+    }
+    Ok(response)
+}
+
+/// Tells whether attestation is stored, without sending it.
+async fn attest_head(hash: String, attest_db: AttestationDB) -> warp::http::StatusCode {
+    match attest_db.read().await.contains(&hash) {
+        Ok(true) => warp::http::StatusCode::OK,
+        Ok(false) => warp::http::StatusCode::NOT_FOUND,
+        Err(e) => {
+            log::warn!("{:#}", e);
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(hash = %hash))]
+async fn attest_delete(hash: String, attest_db: AttestationDB) -> Result<impl Reply, ApiError> {
+    let mut attest_db = attest_db.write().await;
+    attest_db
+        .remove(&hash)
+        .map_err(|e| ApiError::SomeError(e.to_string()))?
+        .ok_or(ApiError::NotFound)?;
+    log::info!("Removed attestation {:?}", hash);
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(skip_all, fields(hash = %hash))]
+async fn attest_status(
+    hash: String,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    if attest_db.read().await.get(&hash).is_none() {
+        return Err(ApiError::NotFound);
+    }
+    let status = controller
+        .read()
+        .await
+        .attestation_status(&hash)
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&serde_json::json!({ "status": status })))
+}
+
+/// Returns the attestation followed by every attestation it chains to,
+/// directly or through other ones.
+#[tracing::instrument(skip_all, fields(hash = %hash))]
+async fn attest_chain(hash: String, attest_db: AttestationDB) -> Result<impl Reply, ApiError> {
+    let attest_db = attest_db.read().await;
+    let mut chain = vec![];
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([hash]);
+    while let Some(hash) = queue.pop_front() {
+        if !visited.insert(hash.clone()) {
+            continue;
+        }
+        let attest = match attest_db.get(&hash) {
+            Some(attest) => attest,
+            None if chain.is_empty() => return Err(ApiError::NotFound),
+            None => return Err(ApiError::MissingEdge(hash)),
+        };
+        queue.extend(storage::edges(&attest.data));
+        chain.push(attest.to_signed_json());
+    }
+    Ok(warp::reply::json(&chain))
+}
+
+/// Fails unless the controller's identifier is incepted, so nothing is signed
+/// under a prefix that isn't established in any KEL.
+fn check_incepted(controller: &Controller) -> Result<(), ApiError> {
+    controller
+        .get_state()
+        .map_err(ApiError::from)?
+        .map(|_| ())
+        .ok_or(ApiError::Controller(ControllerError::NotIncepted))
+}
+
+/// How far ahead of the daemon's clock attestation issuance datetime can be.
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// Fails if attestation carries issuance datetime (`a.dt`) that isn't a valid
+/// RFC 3339 datetime or is implausibly in the future.
+fn check_issued_at<A: Serialize>(attest: &A) -> Result<(), ApiError> {
+    let issued_at = match storage::issued_at(attest) {
+        Some(issued_at) => issued_at,
+        None => return Ok(()),
+    };
+    let parsed = chrono::DateTime::parse_from_rfc3339(&issued_at).map_err(|_| {
+        ApiError::InvalidRequest(format!("Invalid attestation datetime {:?}", issued_at))
+    })?;
+    if parsed > chrono::Utc::now() + chrono::Duration::seconds(MAX_CLOCK_SKEW_SECS) {
+        return Err(ApiError::InvalidRequest(format!(
+            "Attestation datetime {} is in the future",
+            issued_at
+        )));
+    }
+    Ok(())
+}
+
+/// Fails if an attestation chains to one that isn't stored.
+fn check_edges(edges: &[String], attest_db: &AttestationStore) -> Result<(), ApiError> {
+    match edges.iter().find(|edge| attest_db.get(edge).is_none()) {
+        Some(missing) => Err(ApiError::MissingEdge(missing.clone())),
+        None => Ok(()),
+    }
+}
+
+#[tracing::instrument(skip_all, fields(hash = %hash))]
+async fn attest_revoke(
+    hash: String,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    let controller = controller.read().await;
+    // Only attestations issued by this controller can be revoked.
+    match attest_db.read().await.get(&hash) {
+        Some(attest) if attest.data.get_author_id() == controller.get_prefix().to_str() => (),
+        _ => return Err(ApiError::NotFound),
+    }
+    let event = controller.revoke(&hash).await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&event))
+}
+
+/// Signs and stores attestation. Requests repeated with the same
+/// `Idempotency-Key` return the attestation created by the first one. An
+/// attestation already created from the same content is returned with
+/// `409 conflict`, without signing it again.
+async fn attest_create(
+    attest: Attestation,
+    accept: Option<String>,
+    idempotency_key: Option<String>,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    // Store stays locked until the key is recorded, so concurrent retries
+    // can't both sign. The controller is locked first, like everywhere else.
+    let controller = controller.read().await;
+    let mut attest_db = attest_db.write().await;
+    let previous = match &idempotency_key {
+        Some(key) => attest_db
+            .get_idempotent(key)
+            .map_err(|e| ApiError::SomeError(e.to_string()))?
+            .and_then(|hash| attest_db.get(&hash)),
+        None => None,
+    };
+    let (attest, status) = match previous {
+        Some(attest) => {
+            log::info!("Returning attestation created earlier for idempotency key");
+            (attest, warp::http::StatusCode::OK)
+        }
+        None => match create_attestation(attest, &controller, &mut attest_db) {
+            Ok(attest) => {
+                if let Some(key) = &idempotency_key {
+                    attest_db
+                        .insert_idempotent(key, &attest.data.get_hash().to_string())
+                        .map_err(|e| ApiError::SomeError(e.to_string()))?;
+                }
+                (attest, warp::http::StatusCode::OK)
+            }
+            Err(ApiError::AlreadyCreated(hash)) => {
+                log::info!("Returning attestation {} created earlier", hash);
+                let attest = attest_db.get(&hash).ok_or(ApiError::AlreadyCreated(hash))?;
+                (attest, warp::http::StatusCode::CONFLICT)
+            }
+            Err(e) => return Err(e),
+        },
+    };
+
+    let format = AttestationFormat::from_accept(accept);
+    Ok(warp::reply::with_status(
+        format.reply(format.render(&attest)),
+        status,
+    ))
+}
+
+async fn attest_create_batch(
+    attests: Vec<serde_json::Value>,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    let controller = controller.read().await;
+    let mut attest_db = attest_db.write().await;
+    let results = attests
+        .into_iter()
+        .map(|attest| {
+            let result = serde_json::from_value(attest)
+                .map_err(|_| ApiError::InvalidAttestation)
+                .and_then(|attest| create_attestation(attest, &controller, &mut attest_db));
+            match result {
+                Ok(attest) => serde_json::json!({ "attestation": attest.to_signed_json() }),
+                Err(e) => serde_json::json!({ "error": e.name(), "message": e.message() }),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&results))
+}
+
+/// Signs attestation as the controller and stores it.
+#[tracing::instrument(skip_all, fields(hash = tracing::field::Empty))]
+fn create_attestation(
+    attest: Attestation,
+    controller: &Controller,
+    attest_db: &mut AttestationStore,
+) -> Result<SignedAttestation, ApiError> {
+    check_incepted(controller)?;
+    check_new_attestation(&attest)?;
+    check_issued_at(&attest)?;
+    check_edges(&storage::edges(&attest), attest_db)?;
+
+    // Hash
+    let attest = Hashed::new(Attestation {
+        issuer: controller.get_prefix().to_str(),
+        ..attest
+    });
+    let attest_hash = attest.get_hash().to_string();
+    tracing::Span::current().record("hash", &attest_hash.as_str());
+    if attest_db.get(&attest_hash).is_some() {
+        return Err(ApiError::AlreadyCreated(attest_hash));
+    }
+
+    // Sign
+    let sigs = {
+        let msg = &Signed::get_json_bytes(&attest);
+        controller
+            .sign_all(msg)
+            .map_err(|e| ApiError::SomeError(e.to_string()))?
+    };
+    let attest =
+        Signed::new_with_keri_signatures(attest, &sigs).map_err(|_| (ApiError::SigningError))?;
+
+    // Save
+    attest_db
+        .insert(attest_hash.clone(), attest.clone())
+        .map_err(|e| ApiError::SomeError(e.to_string()))?;
+    log::info!("Created attestation {:?}", attest_hash);
+    Metrics::increment(&controller.metrics().attestations_created);
+
+    Ok(attest)
+}
+
+/// Checks that attestation to be signed references a schema and has
+/// attributes (`a`) with non-empty names.
+fn check_new_attestation(attest: &Attestation) -> Result<(), ApiError> {
+    let attest = serde_json::to_value(attest).map_err(|e| ApiError::SomeError(e.to_string()))?;
+    match attest.get("s").and_then(serde_json::Value::as_str) {
+        Some(schema) if !schema.is_empty() => (),
+        _ => return Err(ApiError::MissingField("s")),
+    }
+    match attest.get("a").and_then(serde_json::Value::as_object) {
+        Some(attributes) if !attributes.is_empty() => {
+            if attributes.keys().any(String::is_empty) {
+                return Err(ApiError::MissingField("a: attribute name"));
+            }
+        }
+        _ => return Err(ApiError::MissingField("a")),
+    }
+    Ok(())
+}
+
+async fn attest_receive(
+    attest: warp::hyper::body::Bytes,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+    issuer_allowlist: IssuerAllowlist,
+    // dht_node: Arc<RwLock<Node>>,
+) -> Result<Box<dyn Reply>, ApiError> {
+    let signed_attest = std::str::from_utf8(&attest).map_err(|_| ApiError::InvalidAttestation)?;
+    let (attest, valid) = receive_attestation(
+        signed_attest,
+        &attest_db,
+        &*controller.read().await,
+        &issuer_allowlist,
+    )
+    .await?;
+    if valid {
+        Ok(Box::new(warp::reply::json(&attest.data)))
+    } else {
+        Ok(Box::new(warp::reply::with_status(
             warp::reply::json(&attest.data),
             warp::http::StatusCode::FORBIDDEN,
-        ))),
+        )))
+    }
+}
+
+/// Checks received attestation against the issuer allowlist, its schema and
+/// issuer's keys and stores it if it's valid. Returns the attestation and
+/// whether it was valid.
+#[tracing::instrument(
+    skip_all,
+    fields(hash = tracing::field::Empty, issuer = tracing::field::Empty)
+)]
+async fn receive_attestation(
+    signed_attest: &str,
+    attest_db: &AttestationDB,
+    controller: &Controller,
+    issuer_allowlist: &HashSet<String>,
+) -> Result<(SignedAttestation, bool), ApiError> {
+    // Parse
+    let attest = parse_signed_attestation(signed_attest)?;
+    check_issued_at(&attest.data)?;
+    let attest_issuer = attest.data.get_author_id();
+    // Checked before anything is looked up or stored.
+    if !issuer_allowlist.is_empty() && !issuer_allowlist.contains(attest_issuer) {
+        return Err(ApiError::IssuerNotAllowed(attest_issuer.to_string()));
+    }
+    let attest_hash = attest.data.get_hash().to_string();
+    tracing::Span::current()
+        .record("hash", &attest_hash.as_str())
+        .record("issuer", &tracing::field::display(&attest_issuer));
+    log::info!(
+        "Received attestation {:?} by {:?}",
+        attest_hash,
+        attest_issuer
+    );
+
+    // Check schema
+    {
+        let attest_data =
+            serde_json::to_value(&attest.data).map_err(|e| ApiError::SomeError(e.to_string()))?;
+        let schema_said = attest_data
+            .get("s")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default();
+        let schema = attest_db
+            .read()
+            .await
+            .get_schema(schema_said)
+            .map_err(|e| ApiError::SomeError(e.to_string()))?;
+        match schema {
+            Some(attest_schema) => {
+                let attributes = attest_data.get("a").unwrap_or(&serde_json::Value::Null);
+                schema::validate(&attest_schema, attributes).map_err(ApiError::SchemaViolation)?;
+            }
+            None => log::warn!(
+                "Schema {:?} of attestation {:?} isn't registered",
+                schema_said,
+                attest_hash
+            ),
+        }
+    }
+
+    check_edges(&storage::edges(&attest.data), &*attest_db.read().await)?;
+
+    // Verify
+    let valid = verify_attestation(&attest, controller).await?;
+
+    let metrics = controller.metrics();
+    // Save
+    if valid {
+        Metrics::increment(&metrics.attestations_received);
+        let mut attest_db = attest_db.write().await;
+        match attest_db.get(&attest_hash) {
+            // Resent attestation, nothing to store.
+            Some(stored) if stored.to_signed_json() == attest.to_signed_json() => (),
+            Some(_) => return Err(ApiError::Conflict),
+            None => attest_db
+                .insert(attest_hash, attest.clone())
+                .map_err(|e| ApiError::SomeError(e.to_string()))?,
+        }
+    } else {
+        Metrics::increment(&metrics.attestations_rejected);
+    }
+    Ok((attest, valid))
+}
+
+/// Name of the file offered by the export route.
+const EXPORT_FILE_NAME: &str = "attestations.ndjson";
+
+async fn attest_export(attest_db: AttestationDB) -> Result<impl Reply, Infallible> {
+    let attest_db = attest_db.read().await;
+    let mut attests = attest_db.iter().collect::<Vec<_>>();
+    attests.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let export = attests
+        .into_iter()
+        .map(|(_hash, attest)| attest.to_signed_json() + "\n")
+        .collect::<String>();
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(export, "Content-Type", "application/x-ndjson"),
+        "Content-Disposition",
+        format!("attachment; filename=\"{}\"", EXPORT_FILE_NAME),
+    ))
+}
+
+/// Receives every attestation of an export, one signed attestation per line.
+async fn attest_import(
+    export: warp::hyper::body::Bytes,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+    issuer_allowlist: IssuerAllowlist,
+) -> Result<impl Reply, ApiError> {
+    let export = std::str::from_utf8(&export).map_err(|_| ApiError::InvalidAttestation)?;
+    let controller = controller.read().await;
+    let mut results = vec![];
+    for signed_attest in export.lines().filter(|line| !line.trim().is_empty()) {
+        let received =
+            receive_attestation(signed_attest, &attest_db, &controller, &issuer_allowlist).await;
+        let result = match received {
+            Ok((attest, true)) => {
+                serde_json::json!({ "hash": attest.data.get_hash().to_string() })
+            }
+            Ok((_, false)) => {
+                let e = ApiError::VerificationFailed;
+                serde_json::json!({ "error": e.name(), "message": e.message() })
+            }
+            Err(e) => serde_json::json!({ "error": e.name(), "message": e.message() }),
+        };
+        results.push(result);
+    }
+    Ok(warp::reply::json(&results))
+}
+
+async fn attest_verify(
+    attest: warp::hyper::body::Bytes,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    #[derive(Serialize)]
+    struct Verification {
+        valid: bool,
+        issuer: String,
+        hash: String,
+    }
+
+    let signed_attest = std::str::from_utf8(&attest).map_err(|_| ApiError::InvalidAttestation)?;
+    let attest = parse_signed_attestation(signed_attest)?;
+    let valid = verify_attestation(&attest, &*controller.read().await).await?;
+
+    Ok(warp::reply::json(&Verification {
+        valid,
+        issuer: attest.data.get_author_id().to_string(),
+        hash: attest.data.get_hash().to_string(),
+    }))
+}
+
+/// Verifies attestation, given as signed JSON or as `{"hash": ...}` of a
+/// stored one, and every attestation it chains to. Status of attestations
+/// issued by this controller is checked too, the chain is valid only if none
+/// of them is revoked.
+async fn attest_verify_chain(
+    body: warp::hyper::body::Bytes,
+    attest_db: AttestationDB,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ByHash {
+        hash: String,
+    }
+
+    #[derive(Serialize)]
+    struct ChainNode {
+        hash: String,
+        issuer: Option<String>,
+        valid: bool,
+        /// Known only for attestations issued by this controller.
+        status: Option<AttestationStatus>,
+        error: Option<String>,
+    }
+
+    let (root_hash, root) = match serde_json::from_slice::<ByHash>(&body) {
+        Ok(ByHash { hash }) => (hash, None),
+        Err(_) => {
+            let signed_attest =
+                std::str::from_utf8(&body).map_err(|_| ApiError::InvalidAttestation)?;
+            let attest = parse_signed_attestation(signed_attest)?;
+            (attest.data.get_hash().to_string(), Some(attest))
+        }
+    };
+    let mut nodes = vec![];
+    collect_chain(
+        root_hash,
+        root,
+        &*attest_db.read().await,
+        &mut vec![],
+        &mut HashSet::new(),
+        &mut nodes,
+    )?;
+    if let Some((_, None)) = nodes.first() {
+        return Err(ApiError::NotFound);
+    }
+
+    let controller = controller.read().await;
+    let own_prefix = controller.get_prefix().to_str();
+    let mut report = vec![];
+    for (hash, attest) in nodes {
+        let attest = match attest {
+            Some(attest) => attest,
+            None => {
+                let error = ApiError::MissingEdge(hash.clone());
+                report.push(ChainNode {
+                    hash,
+                    issuer: None,
+                    valid: false,
+                    status: None,
+                    error: Some(error.message()),
+                });
+                continue;
+            }
+        };
+        let issuer = attest.data.get_author_id().to_string();
+        let (valid, error) = match verify_attestation(&attest, &controller).await {
+            Ok(valid) => (valid, None),
+            Err(e) => (false, Some(e.message())),
+        };
+        let status = if issuer == own_prefix {
+            Some(
+                controller
+                    .attestation_status(&hash)
+                    .map_err(ApiError::from)?,
+            )
+        } else {
+            None
+        };
+        report.push(ChainNode {
+            hash,
+            issuer: Some(issuer),
+            valid,
+            status,
+            error,
+        });
+    }
+
+    let valid = report
+        .iter()
+        .all(|node| node.valid && !matches!(node.status, Some(AttestationStatus::Revoked)));
+    Ok(warp::reply::json(&serde_json::json!({
+        "valid": valid,
+        "attestations": report,
+    })))
+}
+
+/// Collects `hash` and every attestation it chains to, depth first, along
+/// with their signed JSON. Attestations that aren't stored are collected as
+/// `None`. `path` holds hashes leading to the current one, so chaining back
+/// to any of them is reported as a cycle.
+fn collect_chain(
+    hash: String,
+    attest: Option<SignedAttestation>,
+    attest_db: &AttestationStore,
+    path: &mut Vec<String>,
+    visited: &mut HashSet<String>,
+    nodes: &mut Vec<(String, Option<SignedAttestation>)>,
+) -> Result<(), ApiError> {
+    if path.contains(&hash) {
+        return Err(ApiError::CyclicChain(hash));
+    }
+    if !visited.insert(hash.clone()) {
+        return Ok(());
+    }
+    let attest = attest.or_else(|| attest_db.get(&hash));
+    let edges = attest
+        .as_ref()
+        .map(|attest| storage::edges(&attest.data))
+        .unwrap_or_default();
+    nodes.push((hash.clone(), attest));
+    path.push(hash);
+    for edge in edges {
+        collect_chain(edge, None, attest_db, path, visited, nodes)?;
+    }
+    path.pop();
+    Ok(())
+}
+
+/// Parses signed attestation and checks that its hash matches its content.
+fn parse_signed_attestation(signed_attest: &str) -> Result<SignedAttestation, ApiError> {
+    let attest = Signed::<Hashed<Attestation>>::from_signed_json(signed_attest)
+        .map_err(|_| ApiError::InvalidAttestation)?;
+    let content = serde_json::to_value(&attest.data)
+        .and_then(serde_json::from_value::<Attestation>)
+        .map_err(|_| ApiError::InvalidAttestation)?;
+    if Hashed::new(content).get_hash() != attest.data.get_hash() {
+        return Err(ApiError::InvalidAttestation);
+    }
+    Ok(attest)
+}
+
+/// Checks attestation signatures against its issuer's current keys. Fails
+/// only if the issuer's keys can't be found.
+async fn verify_attestation(
+    attest: &SignedAttestation,
+    controller: &Controller,
+) -> Result<bool, ApiError> {
+    let issuer = attest.data.get_author_id();
+    let prefix = issuer.parse().unwrap_or_default();
+    let key_config = controller
+        .get_public_keys(&prefix)
+        .await?
+        .ok_or(ApiError::UnknownIssuer)?;
+    if verify_with_keys(attest, issuer, key_config) {
+        return Ok(true);
+    }
+    if !controller.refreshes_stale_keys() {
+        return Ok(false);
+    }
+    // The issuer may have rotated after the resolver that answered got its
+    // key event log.
+    match controller.refresh_public_keys(&prefix).await? {
+        Some(key_config) => Ok(verify_with_keys(attest, issuer, key_config)),
+        None => Ok(false),
+    }
+}
+
+/// Verifies attestation with acdc, which picks the check by the issuer's
+/// key type.
+fn verify_with_keys(attest: &SignedAttestation, issuer: &str, key_config: KeyConfig) -> bool {
+    let keys = {
+        let mut keys = HashMap::new();
+        keys.insert(issuer.to_owned(), PubKey::KeriKeys(key_config));
+        keys
+    };
+    attest.verify(&keys).is_ok()
+}
+
+/// Prefix acdc puts in front of KERI signatures attached to signed JSON.
+const ACDC_SIGNATURES_CODE: &str = "-0K";
+
+/// Splits signed JSON attestation into its JSON body and the attached KERI
+/// signatures.
+fn split_signed_json(signed_attest: &str) -> Result<(&str, &str), ApiError> {
+    // Signatures follow right after the JSON body.
+    let mut json =
+        serde_json::Deserializer::from_str(signed_attest).into_iter::<serde_json::Value>();
+    json.next()
+        .ok_or(ApiError::InvalidAttestation)?
+        .map_err(|_| ApiError::InvalidAttestation)?;
+    let (body, signatures) = signed_attest.split_at(json.byte_offset());
+    Ok((
+        body,
+        signatures
+            .strip_prefix(ACDC_SIGNATURES_CODE)
+            .unwrap_or(signatures),
+    ))
+}
+
+async fn schema_register(
+    schema: warp::hyper::body::Bytes,
+    attest_db: AttestationDB,
+) -> Result<impl Reply, ApiError> {
+    let schema = std::str::from_utf8(&schema)
+        .map_err(|e| ApiError::InvalidRequest(format!("Schema isn't valid UTF-8: {}", e)))?;
+    let said = schema::verify_said(schema)
+        .map_err(|e| ApiError::InvalidRequest(format!("Invalid schema: {:#}", e)))?;
+    attest_db
+        .read()
+        .await
+        .insert_schema(&said, schema)
+        .map_err(|e| ApiError::SomeError(e.to_string()))?;
+    log::info!("Registered schema {:?}", said);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&said),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+async fn get_kel(
+    accept: Option<String>,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    let kel = controller.read().await.get_kel().map_err(ApiError::from)?;
+
+    // KEL is kept as CESR stream, so only the declared content type differs.
+    let content_type = match accept {
+        Some(accept) if accept.contains(CESR_CONTENT_TYPE) => CESR_CONTENT_TYPE,
+        _ => "text/plain",
+    };
+    Ok(warp::reply::with_header(kel, "Content-Type", content_type))
+}
+
+async fn get_kel_events(controller: Arc<RwLock<Controller>>) -> Result<impl Reply, ApiError> {
+    let events = controller
+        .read()
+        .await
+        .get_kel_events()
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&events))
+}
+
+async fn get_state(controller: Arc<RwLock<Controller>>) -> Result<impl Reply, ApiError> {
+    let state = controller
+        .read()
+        .await
+        .get_state()
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::NotFound)?;
+    Ok(warp::reply::json(&state))
+}
+
+async fn get_event_receipts(
+    sn: u64,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    #[derive(Serialize)]
+    struct EventReceipts {
+        sn: u64,
+        count: usize,
+        witnesses: Vec<String>,
+    }
+
+    let controller = controller.read().await;
+    let state = controller
+        .get_state()
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::NotFound)?;
+    if sn > state.sn {
+        return Err(ApiError::NotFound);
+    }
+    let witnesses = controller
+        .get_event_receipts(sn)
+        .map_err(ApiError::from)?
+        .iter()
+        .map(Prefix::to_str)
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&EventReceipts {
+        sn,
+        count: witnesses.len(),
+        witnesses,
+    }))
+}
+
+#[tracing::instrument(skip_all, fields(prefix = %prefix))]
+async fn resolve_state(
+    prefix: String,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    let prefix = prefix.parse().map_err(|e: keri::error::Error| {
+        ApiError::Controller(ControllerError::Parse(e.to_string()))
+    })?;
+    let state = controller
+        .read()
+        .await
+        .get_state_from_resolvers(&prefix)
+        .await
+        .map_err(|e| {
+            log::warn!("Resolving {} failed: {:#}", prefix.to_str(), e);
+            ApiError::NotFound
+        })?;
+    Ok(warp::reply::json(&state))
+}
+
+async fn witnesses(controller: Arc<RwLock<Controller>>) -> Result<impl Reply, ApiError> {
+    let witnesses = controller
+        .read()
+        .await
+        .check_witnesses()
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&witnesses))
+}
+
+async fn health(controller: Arc<RwLock<Controller>>) -> Result<impl Reply, ApiError> {
+    #[derive(Serialize)]
+    struct Health {
+        initialized: bool,
+        reachable: Vec<String>,
+        unreachable: Vec<String>,
+    }
+
+    let controller = controller.read().await;
+    let state = controller.get_state().map_err(ApiError::from)?;
+    let (reachable, unreachable): (Vec<_>, Vec<_>) = controller
+        .check_witnesses()
+        .await
+        .map_err(ApiError::from)?
+        .into_iter()
+        .partition(|w| w.reachable);
+
+    let healthy = match &state {
+        Some(state) => {
+            let reachable = state
+                .witness_config
+                .witnesses
+                .iter()
+                .filter(|w| reachable.iter().any(|status| status.aid == w.to_str()))
+                .cloned()
+                .collect::<Vec<_>>();
+            is_threshold_met(
+                &state.witness_config.tally,
+                &state.witness_config.witnesses,
+                &reachable,
+            )
+        }
+        None => false,
+    };
+    let status = if healthy {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Health {
+            initialized: state.is_some(),
+            reachable: reachable.into_iter().map(|w| w.aid).collect(),
+            unreachable: unreachable.into_iter().map(|w| w.aid).collect(),
+        }),
+        status,
+    ))
+}
+
+async fn get_metrics(controller: Arc<RwLock<Controller>>) -> Result<impl Reply, ApiError> {
+    let controller = controller.read().await;
+    let kel_sn = controller
+        .get_state()
+        .map_err(ApiError::from)?
+        .map(|state| state.sn);
+    Ok(warp::reply::with_header(
+        controller.metrics().render(kel_sn),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+async fn interact(
+    interaction_data: warp::hyper::body::Bytes,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<warp::reply::Html<String>, ApiError> {
+    #[derive(Deserialize)]
+    struct InteractionData {
+        digests: Vec<SelfAddressingPrefix>,
+    }
+    let ixn_data: InteractionData = serde_json::from_slice(&interaction_data)
+        .map_err(|e| ApiError::InvalidRequest(format!("Interaction data is malformed: {}", e)))?;
+    let controller = controller.read().await;
+    controller
+        .interact(&ixn_data.digests)
+        .await
+        .map_err(ApiError::from)?;
+    let current_kel = controller.get_kel().map_err(ApiError::from)?;
+
+    Ok(warp::reply::html(current_kel))
+}
+
+/// Anchors delegated event sent in CESR by the delegated identifier's
+/// controller. Returns the updated key event log.
+async fn delegate(
+    event: warp::hyper::body::Bytes,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<warp::reply::Html<String>, ApiError> {
+    let controller = controller.read().await;
+    controller
+        .anchor_delegated(&event)
+        .await
+        .map_err(ApiError::from)?;
+    let current_kel = controller.get_kel().map_err(ApiError::from)?;
+
+    Ok(warp::reply::html(current_kel))
+}
+
+#[derive(Deserialize)]
+struct SignParams {
+    /// Encoding of the request body, `raw` if not set.
+    encoding: Option<String>,
+}
+
+/// Signs request body with the controller's current key. The signature
+/// comes with its key index and derivation, so the caller can attach it.
+async fn sign(
+    params: SignParams,
+    data: warp::hyper::body::Bytes,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    #[derive(Serialize)]
+    struct Signature {
+        signature: String,
+        index: u16,
+        algorithm: String,
+        prefix: String,
+    }
+
+    let data = match params.encoding.as_deref() {
+        None | Some("raw") => data.to_vec(),
+        Some("base64") => base64::decode(&data)
+            .map_err(|e| ApiError::InvalidRequest(format!("Body isn't valid base64: {}", e)))?,
+        Some(encoding) => {
+            return Err(ApiError::InvalidRequest(format!(
+                "Unknown encoding {}, expected raw or base64",
+                encoding
+            )))
+        }
+    };
+    let controller = controller.read().await;
+    check_incepted(&controller)?;
+    let signature = controller
+        .sign(&data)
+        .map_err(|e| ApiError::SomeError(e.to_string()))?;
+    Ok(warp::reply::json(&Signature {
+        signature: signature.to_str(),
+        index: signature.index,
+        algorithm: format!("{:?}", signature.signature.derivation),
+        prefix: controller.get_prefix().to_str(),
+    }))
+}
+
+async fn import_kel(
+    kel: warp::hyper::body::Bytes,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    let state = controller
+        .read()
+        .await
+        .import_kel(&kel)
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&state))
+}
+
+async fn get_oobi(controller: Arc<RwLock<Controller>>) -> Result<impl Reply, ApiError> {
+    let controller = controller.read().await;
+    let witnesses = controller.get_oobis().await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "prefix": controller.get_prefix().to_str(),
+        "witnesses": witnesses,
+    })))
+}
+
+async fn resolve_oobi(
+    oobi_data: warp::hyper::body::Bytes,
+    controller: Arc<RwLock<Controller>>,
+) -> Result<impl Reply, ApiError> {
+    #[derive(Deserialize)]
+    struct OobiData {
+        url: Url,
     }
+    let oobi_data: OobiData = serde_json::from_slice(&oobi_data)
+        .map_err(|e| ApiError::InvalidRequest(format!("OOBI request is malformed: {}", e)))?;
+    let state = controller
+        .read()
+        .await
+        .resolve_oobi(&oobi_data.url)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(warp::reply::json(&state))
 }
 
 async fn rotate(
     rotation_data: warp::hyper::body::Bytes,
     controller: Arc<RwLock<Controller>>,
-) -> Result<warp::reply::Html<String>, ApiError> {
+) -> Result<impl Reply, ApiError> {
+    /// Without `witness_prefixes` and `threshold` only the keys are rotated.
+    /// Unknown fields are rejected, so a misspelled one doesn't silently
+    /// leave the witnesses unchanged.
     #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
     struct RotationData {
         witness_prefixes: Option<Vec<WitnessConfig>>,
-        threshold: Option<u64>,
+        threshold: Option<ThresholdConfig>,
+        /// Only build the event, without publishing it or rotating keys.
+        #[serde(default)]
+        dry_run: bool,
+        /// Sequence number the last event has to have.
+        expected_sn: Option<u64>,
+        /// Whether signing keys are rotated. Only `true` is accepted: KERI
+        /// pre-rotation makes every rotation establish the keys committed to
+        /// in the previous event, so the current keys can't be kept.
+        rotate_keys: Option<bool>,
     }
-    let rot_data: RotationData =
-        serde_json::from_slice(&rotation_data).map_err(|e| ApiError::SomeError(e.to_string()))?;
+    #[derive(Serialize)]
+    struct Rotation {
+        dry_run: bool,
+        event: String,
+        digest: String,
+        sn: u64,
+        keys: Vec<String>,
+        witnesses: Vec<String>,
+        /// Missing in dry run, because the KEL doesn't change.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        kel: Option<String>,
+    }
+    let rot_data: RotationData = serde_json::from_slice(&rotation_data)
+        .map_err(|e| ApiError::InvalidRequest(format!("Rotation data is malformed: {}", e)))?;
     let witness_prefixes = match rot_data.witness_prefixes {
         Some(prefixes) => {
             if prefixes.is_empty() {
@@ -218,18 +1969,298 @@ async fn rotate(
         }
         None => None,
     };
-    controller
-        .write()
-        .await
-        .rotate(witness_prefixes, rot_data.threshold)
-        .await
-        .map_err(|e| ApiError::SomeError(e.to_string()))?;
-    let current_kel = controller
-        .read()
+    let threshold = rot_data
+        .threshold
+        .map(|threshold| threshold.to_threshold())
+        .transpose()
+        .map_err(ApiError::Controller)?;
+    if rot_data.rotate_keys == Some(false) {
+        return Err(ApiError::InvalidRequest(
+            "rotate_keys can't be false: every rotation has to establish the keys committed to \
+             in the previous event"
+                .into(),
+        ));
+    }
+    let controller = controller.read().await;
+    let (event, state, kel) = if rot_data.dry_run {
+        let (event, projected_state) = controller
+            .preview_rotation(witness_prefixes, threshold, rot_data.expected_sn)
+            .await
+            .map_err(ApiError::from)?;
+        (event, projected_state, None)
+    } else {
+        let (event, state) = controller
+            .rotate(witness_prefixes, threshold, rot_data.expected_sn)
+            .await
+            .map_err(ApiError::from)?;
+        (
+            event,
+            state,
+            Some(controller.get_kel().map_err(ApiError::from)?),
+        )
+    };
+
+    Ok(warp::reply::json(&Rotation {
+        dry_run: rot_data.dry_run,
+        event,
+        digest: state.last_event_digest.to_str(),
+        sn: state.sn,
+        keys: state
+            .current
+            .public_keys
+            .iter()
+            .map(|key| key.to_str())
+            .collect(),
+        witnesses: state
+            .witness_config
+            .witnesses
+            .iter()
+            .map(|w| w.to_str())
+            .collect(),
+        kel,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        controller::{InceptionConfig, NetworkConfig},
+        keys::KeyStore,
+        DelegatorConfig,
+    };
+
+    /// Controller whose identifier is incepted without witnesses.
+    async fn incepted_controller(db_path: &Path) -> anyhow::Result<Controller> {
+        Controller::init(
+            db_path,
+            vec![],
+            InceptionConfig::default(),
+            None,
+            KeyStore::ephemeral(),
+            NetworkConfig::default(),
+        )
         .await
-        .get_kel()
-        .map_err(|e| ApiError::SomeError(e.to_string()))?;
+    }
 
-    // TODO Should it return current kel?
-    Ok(warp::reply::html(current_kel))
+    fn attestation(name: &str) -> Attestation {
+        serde_json::from_value(serde_json::json!({
+            "a": {"name": name},
+            "i": "issuer",
+            "p": [],
+            "r": [],
+            "s": "E46jrVPTzlSkUPqGGeIZ8a8FWS7a6s4reAXRZOkogZ2A",
+            "v": "ACDC10JSON00011c_",
+        }))
+        .expect("attestation is malformed")
+    }
+
+    #[tokio::test]
+    async fn attestation_with_mutated_content_is_rejected() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = incepted_controller(&dir.path().join("kel")).await?;
+        let mut attest_db = AttestationStore::open(&dir.path().join("attestations"), None)?;
+        let signed_attest = create_attestation(attestation("John"), &controller, &mut attest_db)
+            .expect("creating attestation failed")
+            .to_signed_json();
+
+        // Content changes after hashing, the claimed hash stays.
+        let mutated = signed_attest.replace("\"John\"", "\"Jane\"");
+
+        assert!(parse_signed_attestation(&signed_attest).is_ok());
+        assert!(matches!(
+            parse_signed_attestation(&mutated),
+            Err(ApiError::InvalidAttestation)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_attestations_of_allowed_issuers_are_received() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = incepted_controller(&dir.path().join("kel")).await?;
+        let mut attest_db = AttestationStore::open(&dir.path().join("attestations"), None)?;
+        let signed_attest = create_attestation(attestation("John"), &controller, &mut attest_db)
+            .expect("creating attestation failed")
+            .to_signed_json();
+        let attest_db = Arc::new(RwLock::new(attest_db));
+        let allowed = HashSet::from([controller.get_prefix().to_str()]);
+        let other = HashSet::from(["DA_REI14n-2_ev3ZdXtwcxbnbfLN-13G4LwcTdSZ-_Kc".to_string()]);
+
+        let received = receive_attestation(&signed_attest, &attest_db, &controller, &allowed).await;
+        let rejected = receive_attestation(&signed_attest, &attest_db, &controller, &other).await;
+
+        assert!(matches!(received, Ok((_, true))));
+        assert!(matches!(rejected, Err(ApiError::IssuerNotAllowed(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creating_same_attestation_twice_conflicts() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = Arc::new(RwLock::new(
+            incepted_controller(&dir.path().join("kel")).await?,
+        ));
+        let attest_db = Arc::new(RwLock::new(AttestationStore::open(
+            &dir.path().join("attestations"),
+            None,
+        )?));
+
+        let mut replies = vec![];
+        for _ in 0..2 {
+            let reply = attest_create(
+                attestation("John"),
+                None,
+                None,
+                attest_db.clone(),
+                controller.clone(),
+            )
+            .await
+            .expect("creating attestation failed")
+            .into_response();
+            replies.push(reply);
+        }
+
+        assert_eq!(replies[0].status(), warp::http::StatusCode::OK);
+        assert_eq!(replies[1].status(), warp::http::StatusCode::CONFLICT);
+        assert_eq!(attest_db.read().await.iter().count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_only_instance_rejects_every_mutating_route() -> anyhow::Result<()> {
+        const ATTESTATION: &str = "E46jrVPTzlSkUPqGGeIZ8a8FWS7a6s4reAXRZOkogZ2A";
+        let dir = tempfile::tempdir()?;
+        let served = ServedIdentifier {
+            prefix: "identifier".into(),
+            controller: Arc::new(RwLock::new(
+                incepted_controller(&dir.path().join("kel")).await?,
+            )),
+            attest_db: Arc::new(RwLock::new(AttestationStore::open(
+                &dir.path().join("attestations"),
+                None,
+            )?)),
+        };
+        let routes = setup_routes(&[served], None, HashSet::new(), 1024, true, warp::cors());
+
+        let mutating = [
+            ("POST", "/attestations".to_string()),
+            ("POST", "/attestations/create".to_string()),
+            ("POST", "/attestations/create_batch".to_string()),
+            ("POST", "/attestations/import".to_string()),
+            ("DELETE", format!("/attestations/{}", ATTESTATION)),
+            ("POST", format!("/attestations/{}/revoke", ATTESTATION)),
+            ("POST", "/interact".to_string()),
+            ("POST", "/delegate".to_string()),
+            ("POST", "/sign".to_string()),
+            ("POST", "/rotate".to_string()),
+            ("POST", "/kel/import".to_string()),
+            ("POST", "/oobi".to_string()),
+            ("POST", "/schemas".to_string()),
+        ];
+        for (method, path) in mutating {
+            let response = warp::test::request()
+                .method(method)
+                .path(&path)
+                .body("{}")
+                .reply(&routes)
+                .await;
+            assert_eq!(
+                response.status(),
+                warp::http::StatusCode::METHOD_NOT_ALLOWED,
+                "{} {}",
+                method,
+                path
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotation_keeping_keys_is_rejected() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = Arc::new(RwLock::new(
+            incepted_controller(&dir.path().join("kel")).await?,
+        ));
+        let served = ServedIdentifier {
+            prefix: "identifier".into(),
+            controller: controller.clone(),
+            attest_db: Arc::new(RwLock::new(AttestationStore::open(
+                &dir.path().join("attestations"),
+                None,
+            )?)),
+        };
+        let routes = setup_routes(&[served], None, HashSet::new(), 1024, false, warp::cors());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/rotate")
+            .body(r#"{"rotate_keys": false}"#)
+            .reply(&routes)
+            .await;
+
+        assert_eq!(response.status(), warp::http::StatusCode::BAD_REQUEST);
+        assert_eq!(controller.read().await.get_kel_events()?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delegated_events_wait_for_delegator_anchor() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let delegator = Arc::new(RwLock::new(
+            incepted_controller(&dir.path().join("delegator")).await?,
+        ));
+        let served = ServedIdentifier {
+            prefix: "delegator".into(),
+            controller: delegator.clone(),
+            attest_db: Arc::new(RwLock::new(AttestationStore::open(
+                &dir.path().join("attestations"),
+                None,
+            )?)),
+        };
+        let routes = setup_routes(
+            &[served],
+            None,
+            HashSet::new(),
+            64 * 1024,
+            false,
+            warp::cors(),
+        );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let delegator_prefix = delegator.read().await.get_prefix();
+
+        let delegatee = Controller::init(
+            &dir.path().join("delegatee"),
+            vec![],
+            InceptionConfig::default(),
+            Some(DelegatorConfig {
+                aid: delegator_prefix,
+                location: Url::parse(&format!("http://{}/", addr))?,
+                api_token: None,
+            }),
+            KeyStore::ephemeral(),
+            NetworkConfig::default(),
+        )
+        .await?;
+        delegatee.rotate(None, None, None).await?;
+
+        let delegatee_events = delegatee.get_kel_events()?;
+        assert_eq!(delegatee_events[0].event_type, "dip");
+        assert_eq!(delegatee_events[1].event_type, "drt");
+        let anchors = delegator
+            .read()
+            .await
+            .get_kel_events()?
+            .iter()
+            .flat_map(|event| event.anchors.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(anchors.len(), 2);
+        assert!(anchors
+            .iter()
+            .all(|seal| seal["i"] == delegatee.get_prefix().to_str()));
+        Ok(())
+    }
 }
@@ -1,93 +1,918 @@
 use std::{
     collections::HashMap,
-    path::Path,
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use futures::future::{join_all, try_join_all};
+use futures::{
+    future::{join_all, try_join_all},
+    stream::{self, StreamExt},
+};
 use keri::{
     database::sled::SledEventDatabase,
-    derivation::self_signing::SelfSigning,
+    derivation::{basic::Basic, self_signing::SelfSigning},
     error::Error,
+    event::sections::seal::{DigestSeal, EventSeal, Seal},
     event::sections::{threshold::SignatureThreshold, KeyConfig},
-    event_parsing::SignedEventData,
+    event_message::{
+        event_msg_builder::{EventMsgBuilder, EventType},
+        EventMessage,
+    },
+    event_parsing::{attachment::attachment, Attachment, SignedEventData},
     keri::Keri,
-    prefix::{AttachedSignaturePrefix, BasicPrefix, IdentifierPrefix, Prefix},
-    signer::{CryptoBox, KeyManager},
+    prefix::{
+        AttachedSignaturePrefix, BasicPrefix, IdentifierPrefix, Prefix, SelfAddressingPrefix,
+    },
+    processor::EventProcessor,
+    signer::KeyManager,
     state::IdentifierState,
 };
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+    keys::{KeyStore, KEY_SEED_ENV},
+    metrics::Metrics,
+    tel::{AttestationStatus, Tel, TelEvent},
+    transport::{HttpTransport, MailboxTransport, PublishResponse, WitnessTransport},
+    DelegatorConfig, Url, WitnessConfig,
+};
+
+/// Settings of controller's communication with witnesses and resolvers.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Timeout applied to every witness and resolver request.
+    pub request_timeout: Duration,
+    /// How long witness addresses obtained from resolvers are reused.
+    pub witness_ip_ttl: Duration,
+    /// How long key states obtained from resolvers are reused, also for
+    /// issuer keys used in verification. Nothing is cached if zero.
+    pub key_state_ttl: Duration,
+    /// How many times sending to a witness is retried before giving up.
+    pub publish_retries: u32,
+    /// Delay before the first retry, doubled with every next one.
+    pub publish_backoff: Duration,
+    /// How long publishing to a single witness can take, retries included.
+    /// Witnesses that don't receipt in time count as failed.
+    pub witness_timeout: Duration,
+    /// Whether failed verification is retried once with the issuer's key
+    /// event log fetched from every resolver, in case the keys were stale.
+    pub refresh_stale_keys: bool,
+    /// Delay between polls of witness mailboxes for receipts, and of the
+    /// delegator's key event log for the anchor of a delegated event.
+    pub mailbox_poll_interval: Duration,
+    /// How long the delegator has to anchor a delegated event.
+    pub delegation_timeout: Duration,
+    /// Most requests to witnesses sent at once when publishing, unlimited if
+    /// not set.
+    pub max_concurrent_witness_requests: Option<usize>,
+}
 
-use crate::{Url, WitnessConfig};
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            request_timeout: Duration::from_secs(30),
+            witness_ip_ttl: Duration::from_secs(300),
+            key_state_ttl: Duration::from_secs(30),
+            publish_retries: 3,
+            publish_backoff: Duration::from_millis(500),
+            witness_timeout: Duration::from_secs(10),
+            refresh_stale_keys: true,
+            mailbox_poll_interval: Duration::from_millis(500),
+            delegation_timeout: Duration::from_secs(60),
+            max_concurrent_witness_requests: None,
+        }
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum ControllerError {
+    #[error("Unknown location of witness {}", .0.to_str())]
     MissingIp(BasicPrefix),
+    #[error("Can't resolve location of witness {}", .0.to_str())]
+    WitnessResolution(BasicPrefix),
+    #[error(
+        "Not enough witness receipts: got {received}, {required} required{}",
+        describe_witness_errors(.errors)
+    )]
+    PublishFailed {
+        received: usize,
+        required: usize,
+        failed_witnesses: Vec<BasicPrefix>,
+        /// Why witnesses that answered didn't receipt.
+        errors: Vec<String>,
+    },
+    #[error("Improper witness threshold {threshold:?} for {witnesses} witnesses")]
+    ImproperThreshold {
+        threshold: SignatureThreshold,
+        witnesses: usize,
+    },
+    #[error("Witness threshold {threshold:?} can't be met by {witnesses} witnesses")]
+    ThresholdExceedsWitnesses {
+        threshold: SignatureThreshold,
+        witnesses: usize,
+    },
+    #[error("Witness threshold can't be zero when there are {0} witnesses")]
+    ZeroThreshold(usize),
+    #[error("Signing threshold {threshold:?} can't be met by {keys} keys")]
+    ImproperKeyThreshold {
+        threshold: SignatureThreshold,
+        keys: usize,
+    },
+    #[error("Signature threshold not met")]
+    ThresholdNotMet,
+    #[error("There is no key for signature: {0}")]
+    UnknownSignature(String),
+    #[error("Can't find keys of issuer {}", .0.to_str())]
+    UnknownIssuer(IdentifierPrefix),
+    #[error("Controller isn't incepted yet")]
+    NotIncepted,
+    #[error("Parsing failed: {0}")]
+    Parse(String),
+    #[error("Attestation {0} is already revoked")]
+    AlreadyRevoked(String),
+    #[error("Database at {0:?} is already in use by another process")]
+    DatabaseInUse(PathBuf),
+    #[error("Expected last event {expected}, but it's {current}")]
+    UnexpectedSn { expected: u64, current: u64 },
+    #[error("Delegation rejected: {0}")]
+    DelegationRejected(String),
+    #[error("Delegator {} didn't anchor the event in time", .0.to_str())]
+    DelegationTimeout(IdentifierPrefix),
+}
+
+/// Witnesses, witness threshold and signing threshold an identifier is
+/// incepted with.
+#[derive(Default)]
+pub struct InceptionConfig {
+    pub witnesses: Option<Vec<WitnessConfig>>,
+    pub witness_threshold: Option<SignatureThreshold>,
+    /// Signatures of current and next keys required, 1 if not set.
+    pub signing_threshold: Option<SignatureThreshold>,
+}
+
+/// Witness threshold as written in config and requests: the number of
+/// witnesses that have to receipt an event, or a list of fractional witness
+/// weights, like `["1/2", "1/2", "1/2"]`, which have to sum up to at least 1.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ThresholdConfig {
+    Simple(u64),
+    Weighted(Vec<String>),
+}
+
+impl ThresholdConfig {
+    pub fn to_threshold(&self) -> Result<SignatureThreshold, ControllerError> {
+        match self {
+            ThresholdConfig::Simple(t) => Ok(SignatureThreshold::Simple(*t)),
+            ThresholdConfig::Weighted(weights) => Ok(SignatureThreshold::single_weighted(
+                weights
+                    .iter()
+                    .map(|weight| parse_weight(weight))
+                    .collect::<Result<_, _>>()?,
+            )),
+        }
+    }
+}
+
+/// Parses threshold given on the command line, a number or comma separated
+/// weights.
+impl FromStr for ThresholdConfig {
+    type Err = ControllerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('/') || s.contains(',') {
+            let weighted = ThresholdConfig::Weighted(
+                s.split(',')
+                    .map(|weight| weight.trim().to_string())
+                    .collect(),
+            );
+            weighted.to_threshold()?;
+            Ok(weighted)
+        } else {
+            s.parse()
+                .map(ThresholdConfig::Simple)
+                .map_err(|_| ControllerError::Parse(format!("Invalid witness threshold {:?}", s)))
+        }
+    }
+}
+
+fn describe_witness_errors(errors: &[String]) -> String {
+    if errors.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", errors.join("; "))
+    }
+}
+
+fn parse_weight(weight: &str) -> Result<(u64, u64), ControllerError> {
+    let invalid = || ControllerError::Parse(format!("Invalid witness weight {:?}", weight));
+    let (numerator, denominator) = weight.split_once('/').unwrap_or((weight, "1"));
+    let numerator = numerator.trim().parse().map_err(|_| invalid())?;
+    match denominator.trim().parse() {
+        Ok(0) | Err(_) => Err(invalid()),
+        Ok(denominator) => Ok((numerator, denominator)),
+    }
+}
+
+/// Key of the controller's own prefix in the identifier database.
+const IDENTIFIER_KEY: &[u8] = b"prefix";
+
+/// After this many consecutive failures a resolver is asked only when the
+/// other ones fail too.
+const RESOLVER_FAILURE_LIMIT: u32 = 3;
+/// How long a failing resolver stays deprioritized.
+const RESOLVER_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Consecutive failures of a resolver.
+#[derive(Default)]
+struct ResolverHealth {
+    failures: u32,
+    last_failure: Option<Instant>,
 }
 
+impl ResolverHealth {
+    fn is_deprioritized(&self) -> bool {
+        self.failures >= RESOLVER_FAILURE_LIMIT
+            && self
+                .last_failure
+                .map(|failure| failure.elapsed() < RESOLVER_COOLDOWN)
+                .unwrap_or(false)
+    }
+}
+
+/// Witness changes of a rotation, checked against the current state.
+struct RotationPlan {
+    state: IdentifierState,
+    /// Requested witnesses, whose locations are saved only once the rotation
+    /// is carried out.
+    witness_config: Vec<WitnessConfig>,
+    /// Witness set after the rotation.
+    witnesses: Vec<BasicPrefix>,
+    witness_to_add: Option<Vec<BasicPrefix>>,
+    witness_to_remove: Option<Vec<BasicPrefix>>,
+    threshold: SignatureThreshold,
+}
+
+impl RotationPlan {
+    /// Returns state identifier would have after `rotation`.
+    fn project(&self, rotation: &EventMessage) -> Result<IdentifierState> {
+        Ok(self.state.clone().apply(rotation)?)
+    }
+}
+
+/// Result of probing a single witness.
+#[derive(Serialize)]
+pub struct WitnessStatus {
+    pub aid: String,
+    pub location: Option<Url>,
+    pub reachable: bool,
+}
+
+/// Witness endpoint role of the controller's identifier, with the OOBI under
+/// which the witness serves its key event log.
+#[derive(Serialize)]
+pub struct WitnessOobi {
+    pub eid: String,
+    pub role: &'static str,
+    pub url: Url,
+    pub oobi: Url,
+}
+
+/// Event kept in the database until enough witnesses receipted it, so
+/// publishing can be resumed after a crash.
+#[derive(Serialize, Deserialize)]
+struct PendingEvent {
+    /// Signed event in CESR.
+    event: String,
+    witnesses: Vec<BasicPrefix>,
+    threshold: SignatureThreshold,
+}
+
+/// Decoded key event of the controller's KEL.
+#[derive(Serialize)]
+pub struct KelEventSummary {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub sn: u64,
+    pub digest: String,
+    /// Signing keys established by the event.
+    pub keys: Vec<String>,
+    /// Digests of the keys committed to for the next rotation.
+    pub next_keys: Vec<String>,
+    pub witness_threshold: Option<serde_json::Value>,
+    /// Witnesses of an inception, or witnesses added by a rotation.
+    pub witnesses_added: Vec<String>,
+    pub witnesses_removed: Vec<String>,
+    /// Seals anchored by the event.
+    pub anchors: Vec<serde_json::Value>,
+}
+
+/// KERI controller of the daemon's identifier.
+///
+/// Operations that add events to the KEL (`rotate`, `interact`, `revoke`)
+/// are serialized end to end by `event_lock`: the next one reads the key
+/// state only after the previous event was published to witnesses and
+/// saved. They take `&self`, so a controller shared behind a `RwLock` needs
+/// only the read guard for them and reads aren't blocked while witnesses are
+/// contacted.
 pub struct Controller {
+    /// Resolvers in the order they are asked.
     resolver_addresses: Vec<Url>,
-    saved_witnesses: HashMap<String, Url>,
-    controller: Keri<CryptoBox>,
+    resolver_health: Mutex<HashMap<Url, ResolverHealth>>,
+    saved_witnesses: Mutex<HashMap<String, Url>>,
+    /// Persistent copy of `saved_witnesses`.
+    witness_db: sled::Db,
+    /// Prefix of the identifier incepted in this database, kept under
+    /// `IDENTIFIER_KEY`.
+    identifier_db: sled::Tree,
+    /// Events not receipted by enough witnesses yet, by event digest.
+    pending_db: sled::Tree,
+    /// Witness addresses obtained from resolvers, with the time they were
+    /// obtained.
+    resolved_witnesses: Mutex<HashMap<String, (Url, Instant)>>,
+    /// Key states obtained from resolvers, with the time they were obtained.
+    resolved_states: Mutex<HashMap<String, (IdentifierState, Instant)>>,
+    /// Issuer keys processed from key event logs obtained from resolvers,
+    /// with the time the logs were obtained.
+    resolved_keys: Mutex<HashMap<String, (KeyConfig, Instant)>>,
+    tel: Tel,
+    /// Referenced by tasks that publish to slow witnesses in the background.
+    controller: Arc<Keri<KeyStore>>,
+    transport: Arc<dyn WitnessTransport>,
+    /// Transports of witnesses which leave receipts in a mailbox, by witness
+    /// identifier. Other witnesses are reached with `transport`.
+    mailbox_transports: Mutex<HashMap<String, Arc<dyn WitnessTransport>>>,
+    /// Persistent mailbox paths of `mailbox_transports`.
+    mailbox_db: sled::Tree,
+    /// Controller's identifier, once it's incepted or loaded from the
+    /// database. `Keri` only knows identifiers it incepted itself, so events
+    /// of this one are built, signed and processed here. A read-only
+    /// instance serves it with throwaway keys, nothing is signed then.
+    prefix: Option<IdentifierPrefix>,
+    /// Held while an event of the controller is built, published and saved.
+    event_lock: tokio::sync::Mutex<()>,
+    /// Identifier anchoring inception and rotations of a delegated
+    /// identifier, not set for other identifiers.
+    delegator: Option<DelegatorConfig>,
+    client: Client,
+    metrics: Arc<Metrics>,
+    network_config: NetworkConfig,
 }
 
 impl Controller {
-    pub fn new(db_path: &Path, resolver_addresses: Vec<Url>) -> Result<Self> {
+    pub fn new(
+        db_path: &Path,
+        resolver_addresses: Vec<Url>,
+        key_store: KeyStore,
+        network_config: NetworkConfig,
+    ) -> Result<Self> {
+        // Witness database is opened first, so a running instance is
+        // reported clearly instead of failing inside the event database.
+        let witness_db = open_sled(&db_path.join("witnesses"))?;
+        let identifier_db = witness_db.open_tree("identifier")?;
+        let pending_db = witness_db.open_tree("pending_events")?;
+        let mailbox_db = witness_db.open_tree("mailboxes")?;
         let db = Arc::new(SledEventDatabase::new(db_path)?);
+        let saved_witnesses = witness_db
+            .iter()
+            .map(|entry| -> Result<_> {
+                let (aid, location) = entry?;
+                Ok((
+                    String::from_utf8(aid.to_vec())?,
+                    base_url(Url::parse(std::str::from_utf8(&location)?)?),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>>>()
+            .context("Loading saved witness locations failed")?;
 
-        let key_manager = { Arc::new(Mutex::new(CryptoBox::new()?)) };
+        let key_manager = Arc::new(Mutex::new(key_store));
         let keri_controller = Keri::new(Arc::clone(&db), key_manager)?;
+        let client = Client::builder()
+            .timeout(network_config.request_timeout)
+            .build()?;
+        let mailbox_transports = mailbox_db
+            .iter()
+            .map(|entry| -> Result<(String, Arc<dyn WitnessTransport>)> {
+                let (aid, mailbox_path) = entry?;
+                Ok((
+                    String::from_utf8(aid.to_vec())?,
+                    Arc::new(MailboxTransport::new(
+                        client.clone(),
+                        network_config.clone(),
+                        String::from_utf8(mailbox_path.to_vec())?,
+                    )),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>>>()
+            .context("Loading saved witness mailboxes failed")?;
 
         Ok(Controller {
-            controller: keri_controller,
-            resolver_addresses,
-            saved_witnesses: HashMap::new(),
+            controller: Arc::new(keri_controller),
+            prefix: None,
+            event_lock: tokio::sync::Mutex::new(()),
+            delegator: None,
+            resolver_addresses: resolver_addresses.into_iter().map(base_url).collect(),
+            resolver_health: Mutex::new(HashMap::new()),
+            saved_witnesses: Mutex::new(saved_witnesses),
+            witness_db,
+            identifier_db,
+            pending_db,
+            resolved_witnesses: Mutex::new(HashMap::new()),
+            resolved_states: Mutex::new(HashMap::new()),
+            resolved_keys: Mutex::new(HashMap::new()),
+            tel: Tel::open(&db_path.join("tel"))?,
+            transport: Arc::new(HttpTransport::new(client.clone(), network_config.clone())),
+            mailbox_transports: Mutex::new(mailbox_transports),
+            mailbox_db,
+            client,
+            metrics: Arc::new(Metrics::default()),
+            network_config,
         })
     }
 
+    /// Opens the controller database of an identifier incepted by another
+    /// instance, to serve its public state. No keys are loaded and nothing
+    /// is incepted.
+    pub fn open_read_only(
+        db_path: &Path,
+        resolver_addresses: Vec<Url>,
+        network_config: NetworkConfig,
+    ) -> Result<Self> {
+        let mut controller = Controller::new(
+            db_path,
+            resolver_addresses,
+            KeyStore::ephemeral(),
+            network_config,
+        )?;
+        let state = controller.stored_identifier()?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "No identifier is stored in {:?} to serve read-only",
+                db_path
+            )
+        })?;
+        log::info!(
+            "Serving identifier {} at sn {} read-only",
+            state.prefix.to_str(),
+            state.sn
+        );
+        controller.prefix = Some(state.prefix);
+        Ok(controller)
+    }
+
+    /// Opens the controller database and loads the identifier incepted in it
+    /// before. If there's none, the identifier is incepted as `inception`
+    /// says, with every key of `key_store`, delegated by `delegator` if it's
+    /// set. Keys that don't control the stored identifier are an error,
+    /// rather than a reason to incept another one.
     pub async fn init(
         db_path: &Path,
         resolver_addresses: Vec<Url>,
-        initial_witnesses: Option<Vec<WitnessConfig>>,
-        initial_threshold: Option<SignatureThreshold>,
+        inception: InceptionConfig,
+        delegator: Option<DelegatorConfig>,
+        key_store: KeyStore,
+        network_config: NetworkConfig,
+    ) -> Result<Self> {
+        let mut controller =
+            Controller::new(db_path, resolver_addresses, key_store, network_config)?;
+        controller.delegator = delegator;
+        if controller
+            .load_identifier()
+            .with_context(|| format!("Loading identifier stored in {:?} failed", db_path))?
+            .is_none()
+        {
+            controller.incept(inception).await?;
+        }
+        controller
+            .republish_pending()
+            .await
+            .context("Publishing pending events failed")?;
+        Ok(controller)
+    }
+
+    /// Opens the controller database and loads the identifier incepted in it
+    /// before, failing if there's none. Nothing is incepted, so one-shot
+    /// operations can't create an identifier by accident.
+    pub async fn open(
+        db_path: &Path,
+        resolver_addresses: Vec<Url>,
+        delegator: Option<DelegatorConfig>,
+        key_store: KeyStore,
+        network_config: NetworkConfig,
     ) -> Result<Self> {
-        let mut controller = Controller::new(db_path, resolver_addresses)?;
-        let initial_witnesses_prefixes = controller
-            .save_witness_data(&initial_witnesses.unwrap_or_default())
+        let mut controller =
+            Controller::new(db_path, resolver_addresses, key_store, network_config)?;
+        controller.delegator = delegator;
+        controller
+            .load_identifier()
+            .with_context(|| format!("Loading identifier stored in {:?} failed", db_path))?
+            .ok_or_else(|| {
+                anyhow::anyhow!("No identifier is stored in {:?}, incept it first", db_path)
+            })?;
+        controller
+            .republish_pending()
+            .await
+            .context("Publishing pending events failed")?;
+        Ok(controller)
+    }
+
+    /// Loads identifier previously incepted in the database, if there's one,
+    /// and checks the keys control it and the configured delegator is its
+    /// delegator. Returns its state.
+    fn load_identifier(&mut self) -> Result<Option<IdentifierState>> {
+        let state = match self.stored_identifier()? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        self.prefix = Some(state.prefix.clone());
+        let delegated_by = kel_events(self.get_kel()?.as_bytes())?
+            .first()
+            .map(|inception| event_field(inception, "di").to_string())
+            .unwrap_or_default();
+        let configured = self
+            .delegator
+            .as_ref()
+            .map(|delegator| delegator.aid.to_str())
+            .unwrap_or_default();
+        if delegated_by != configured {
+            self.prefix = None;
+            return Err(match (delegated_by.is_empty(), configured.is_empty()) {
+                (true, _) => anyhow::anyhow!(
+                    "Identifier {} isn't delegated, but delegator {} is configured",
+                    state.prefix.to_str(),
+                    configured
+                ),
+                (false, true) => anyhow::anyhow!(
+                    "Identifier {} is delegated by {}, but no delegator is configured",
+                    state.prefix.to_str(),
+                    delegated_by
+                ),
+                (false, false) => anyhow::anyhow!(
+                    "Identifier {} is delegated by {}, but delegator {} is configured",
+                    state.prefix.to_str(),
+                    delegated_by,
+                    configured
+                ),
+            });
+        }
+        // Every rotation moves seeded keys one step further from the
+        // inception keys.
+        let rotations = self
+            .get_kel_events()?
+            .iter()
+            .filter(|event| event.event_type == "rot" || event.event_type == "drt")
+            .count() as u32;
+        {
+            let key_manager = self.controller.key_manager();
+            let mut key_manager = key_manager.lock().map_err(|_| Error::MutexPoisoned)?;
+            let current_keys = &state.current.public_keys;
+            let controls = |keys: Vec<BasicPrefix>| {
+                keys.len() == current_keys.len()
+                    && keys
+                        .iter()
+                        .zip(current_keys)
+                        .all(|(key, current)| key.public_key.key() == current.public_key.key())
+            };
+            key_manager.skip_rotations(rotations)?;
+            if !controls(key_manager.current_keys()) && controls(key_manager.next_keys()) {
+                // The daemon stopped after the rotation was processed, but
+                // before the rotated keys were saved.
+                log::warn!(
+                    "Key store is one rotation behind identifier {}, rotating it",
+                    state.prefix.to_str()
+                );
+                key_manager.rotate()?;
+            }
+            if !controls(key_manager.current_keys()) {
+                self.prefix = None;
+                if key_manager.is_ephemeral() {
+                    return Err(anyhow::anyhow!(
+                        "Keys generated on this start don't control identifier {} incepted \
+                         before. Set key_store_path or {} to keep the keys controlling it",
+                        state.prefix.to_str(),
+                        KEY_SEED_ENV
+                    ));
+                }
+                return Err(anyhow::anyhow!(
+                    "Configured keys don't control identifier {}",
+                    state.prefix.to_str()
+                ));
+            }
+        }
+        log::info!(
+            "Loaded identifier {} at sn {} with witnesses {:?}",
+            state.prefix.to_str(),
+            state.sn,
+            state
+                .witness_config
+                .witnesses
+                .iter()
+                .map(Prefix::to_str)
+                .collect::<Vec<_>>()
+        );
+        Ok(Some(state))
+    }
+
+    /// Incepts the controller's identifier with its current and next keys,
+    /// both under the signing threshold, and publishes the inception event to
+    /// the initial witnesses. A delegated inception is published only after
+    /// the delegator anchored it.
+    async fn incept(&mut self, inception: InceptionConfig) -> Result<()> {
+        let initial_witnesses_prefixes = self
+            .save_witness_data(&inception.witnesses.unwrap_or_default())
+            .await
             .context("Saving initial witness data failed")?;
+        let initial_threshold = inception
+            .witness_threshold
+            .unwrap_or(SignatureThreshold::Simple(0));
 
-        let icp_event = controller
-            .controller
-            .incept(Some(initial_witnesses_prefixes.clone()), initial_threshold)
+        let (keys, next_keys) = {
+            let key_manager = self.controller.key_manager();
+            let key_manager = key_manager.lock().map_err(|_| Error::MutexPoisoned)?;
+            if key_manager.is_ephemeral() {
+                log::warn!(
+                    "Incepting with keys generated for this start only, the identifier \
+                     can't be loaded after a restart. Set key_store_path or {} to keep it",
+                    KEY_SEED_ENV
+                );
+            }
+            (key_manager.current_keys(), key_manager.next_keys())
+        };
+        let signing_threshold = inception
+            .signing_threshold
+            .unwrap_or(SignatureThreshold::Simple(1));
+        validate_key_threshold(keys.len(), &signing_threshold)?;
+        validate_key_threshold(next_keys.len(), &signing_threshold)?;
+        let builder = match &self.delegator {
+            Some(delegator) => {
+                EventMsgBuilder::new(EventType::DelegatedInception).with_delegator(&delegator.aid)
+            }
+            None => EventMsgBuilder::new(EventType::Inception),
+        };
+        let icp_event = builder
+            .with_keys(keys)
+            .with_next_keys(next_keys)
+            .with_threshold(&signing_threshold)
+            .with_next_threshold(&signing_threshold)
+            .with_witness_list(&initial_witnesses_prefixes)
+            .with_witness_threshold(&initial_threshold)
+            .build()
             .context("Generating incpetion event failed")?;
-        let icp_event: SignedEventData = (&icp_event).into();
-        println!("\nInception event generated and signed...");
+        let signatures = self.sign_event(&icp_event.serialize()?, false)?;
+        let signed_event = match &self.delegator {
+            Some(delegator) => {
+                let (signed_event, delegator_kel) = self
+                    .process_delegated_event(delegator, &icp_event, &signatures)
+                    .await
+                    .context("Delegated inception failed")?;
+                self.send_delegator_kel(&initial_witnesses_prefixes, &delegator_kel)
+                    .await;
+                signed_event
+            }
+            None => self.process_own_event(&icp_event, &signatures)?,
+        };
+        log::debug!("Inception event generated and signed");
 
-        controller
-            .publish_event(&icp_event, &initial_witnesses_prefixes)
+        // Saved before publishing, so an inception that doesn't get enough
+        // receipts is published again on the next start instead of
+        // incepting another identifier.
+        self.prefix = Some(icp_event.event.prefix.clone());
+        self.identifier_db
+            .insert(IDENTIFIER_KEY, self.get_prefix().to_str().as_bytes())
+            .context("Saving controller identifier failed")?;
+
+        let failed_witnesses = self
+            .publish_event(
+                &signed_event,
+                &initial_witnesses_prefixes,
+                &initial_threshold,
+            )
             .await
             .context("Publishing inception event failed")?;
+        report_failed_witnesses("inception", &failed_witnesses);
 
-        println!(
-            "\nTDA initialized succesfully. \nTda identifier: {}\n",
-            controller.controller.prefix().to_str()
+        log::info!(
+            "Controller initialized with identifier {}",
+            self.get_prefix().to_str()
         );
+        Ok(())
+    }
 
-        Ok(controller)
+    /// Signs serialized key event with every key the controller holds, the
+    /// next keys for a rotation that establishes them. Signatures are
+    /// indexed by the key position.
+    fn sign_event(
+        &self,
+        event: &[u8],
+        with_next_keys: bool,
+    ) -> Result<Vec<AttachedSignaturePrefix>, Error> {
+        let key_manager = self.controller.key_manager();
+        let key_manager = key_manager.lock().map_err(|_| Error::MutexPoisoned)?;
+        let signatures = if with_next_keys {
+            key_manager.sign_all_with_next(event)
+        } else {
+            key_manager.sign_all(event)
+        };
+        Ok(signatures
+            .into_iter()
+            .enumerate()
+            .map(|(index, signature)| {
+                AttachedSignaturePrefix::new(
+                    signature_derivation(&key_manager.key_type()),
+                    signature,
+                    index as u16,
+                )
+            })
+            .collect())
+    }
+
+    /// Processes the controller's own signed event into its KEL. Returns the
+    /// event with its signatures attached, in CESR.
+    fn process_own_event(
+        &self,
+        event: &EventMessage,
+        signatures: &[AttachedSignaturePrefix],
+    ) -> Result<Vec<u8>> {
+        let mut signed_event = event.serialize()?;
+        signed_event.extend(signatures_attachment(signatures).as_bytes());
+        self.process_own_stream(event, signed_event)
+    }
+
+    /// Sends the controller's own signed delegated event to the delegator,
+    /// waits until the delegator anchors it and processes it into the KEL,
+    /// after the delegator's KEL it's checked against. Returns the event with
+    /// its signatures and the seal of the anchoring event attached, in CESR,
+    /// and the delegator's KEL.
+    async fn process_delegated_event(
+        &self,
+        delegator: &DelegatorConfig,
+        event: &EventMessage,
+        signatures: &[AttachedSignaturePrefix],
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut signed_event = event.serialize()?;
+        signed_event.extend(signatures_attachment(signatures).as_bytes());
+        let (delegator_kel, anchor_sn, anchor_digest) = self
+            .request_delegation(delegator, event, &signed_event)
+            .await?;
+        self.controller
+            .parse_and_process(&delegator_kel)
+            .map_err(|e| ControllerError::Parse(e.to_string()))
+            .context("Can't process delegator's key event log")?;
+
+        signed_event.extend(source_seal_attachment(anchor_sn, &anchor_digest).as_bytes());
+        Ok((self.process_own_stream(event, signed_event)?, delegator_kel))
+    }
+
+    /// Sends signed delegated event to the delegator and polls the
+    /// delegator's KEL until an event anchors it. Returns the KEL and the
+    /// sequence number and digest of the anchoring event.
+    async fn request_delegation(
+        &self,
+        delegator: &DelegatorConfig,
+        event: &EventMessage,
+        signed_event: &[u8],
+    ) -> Result<(Vec<u8>, u64, String)> {
+        let mut request = self
+            .client
+            .post(delegator.location.join("delegate")?)
+            .body(signed_event.to_vec());
+        if let Some(api_token) = &delegator.api_token {
+            request = request.bearer_auth(api_token);
+        }
+        request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| {
+                format!(
+                    "Delegator {} didn't accept delegation request",
+                    delegator.aid.to_str()
+                )
+            })?;
+
+        let prefix = event.event.prefix.to_str();
+        let sn = event.event.sn;
+        let digest = kel_events(signed_event)?
+            .first()
+            .map(|event| event_field(event, "d").to_string())
+            .unwrap_or_default();
+        let kel_url = delegator.location.join("kel")?;
+        let anchored = async {
+            loop {
+                let kel = async {
+                    let kel = self
+                        .client
+                        .get(kel_url.clone())
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .bytes()
+                        .await?
+                        .to_vec();
+                    check_kel_prefix(&kel, &delegator.aid)?;
+                    Ok::<_, anyhow::Error>(kel)
+                };
+                match kel.await {
+                    Ok(kel) => {
+                        if let Some((anchor_sn, anchor_digest)) =
+                            find_anchor(&kel, &prefix, sn, &digest)?
+                        {
+                            return Ok::<_, anyhow::Error>((kel, anchor_sn, anchor_digest));
+                        }
+                    }
+                    Err(e) => log::warn!(
+                        "Getting key event log of delegator {} failed: {:#}",
+                        delegator.aid.to_str(),
+                        e
+                    ),
+                }
+                tokio::time::sleep(self.network_config.mailbox_poll_interval).await;
+            }
+        };
+        tokio::time::timeout(self.network_config.delegation_timeout, anchored)
+            .await
+            .map_err(|_| ControllerError::DelegationTimeout(delegator.aid.clone()))?
+    }
+
+    /// Sends the delegator's KEL to witnesses, so they can check the anchor
+    /// of a delegated event before receipting it. Witnesses that fail to get
+    /// it are only reported, publishing the event reports them again.
+    async fn send_delegator_kel(&self, witnesses: &[BasicPrefix], delegator_kel: &[u8]) {
+        let ips = match self.get_ips(witnesses).await {
+            Ok(ips) => ips,
+            Err(e) => {
+                log::warn!("Can't find witnesses to send delegator's KEL to: {:#}", e);
+                return;
+            }
+        };
+        for (witness, ip) in ips {
+            if let Err(e) = self.transport_for(&witness).send(&ip, delegator_kel).await {
+                log::warn!("Sending delegator's KEL to witness {} failed: {:#}", ip, e);
+            }
+        }
+    }
+
+    /// Processes the controller's own event with its attachments into its
+    /// KEL. Returns the stream.
+    fn process_own_stream(&self, event: &EventMessage, signed_event: Vec<u8>) -> Result<Vec<u8>> {
+        self.controller
+            .parse_and_process(&signed_event)
+            .map_err(|e| ControllerError::Parse(e.to_string()))
+            .context("Can't process controller event")?;
+
+        // Events that don't apply to the state are dropped while processing.
+        let (prefix, sn) = (&event.event.prefix, event.event.sn);
+        match self.controller.get_state_for_prefix(prefix)? {
+            Some(state) if state.sn >= sn => Ok(signed_event),
+            _ => Err(ControllerError::Parse(format!(
+                "Event {} of {} wasn't accepted into the KEL",
+                sn,
+                prefix.to_str()
+            ))
+            .into()),
+        }
+    }
+
+    /// Returns how many of `witnesses` requests can be sent at once.
+    fn witness_concurrency(&self, witnesses: usize) -> usize {
+        self.network_config
+            .max_concurrent_witness_requests
+            .unwrap_or(witnesses)
+            .max(1)
+    }
+
+    /// Returns state of the identifier previously incepted in the database,
+    /// if its key event log is there.
+    pub fn stored_identifier(&self) -> Result<Option<IdentifierState>> {
+        let prefix = match self.identifier_db.get(IDENTIFIER_KEY)? {
+            Some(prefix) => parse_prefix::<IdentifierPrefix>(std::str::from_utf8(&prefix)?)?,
+            None => return Ok(None),
+        };
+        Ok(self.controller.get_state_for_prefix(&prefix)?)
     }
 
-    async fn get_ips(&self, witnesses: &[BasicPrefix]) -> Result<Vec<Url>> {
+    #[tracing::instrument(skip_all, fields(witnesses = witnesses.len()))]
+    async fn get_ips(&self, witnesses: &[BasicPrefix]) -> Result<Vec<(BasicPrefix, Url)>> {
         // Try to get ip addresses for witnesses by checking self.saved_witnesses.
-        let (found_ips, missing_ips): (_, Vec<Result<_, ControllerError>>) = witnesses
-            .iter()
-            .map(|w| -> Result<Url, ControllerError> {
-                self.saved_witnesses
-                    .get(&w.to_str())
-                    .map(|i| i.clone())
-                    .ok_or(ControllerError::MissingIp(w.clone()))
-            })
-            .partition(Result::is_ok);
+        let (found_ips, missing_ips): (_, Vec<Result<_, ControllerError>>) = {
+            let saved_witnesses = self
+                .saved_witnesses
+                .lock()
+                .map_err(|_| Error::MutexPoisoned)?;
+            witnesses
+                .iter()
+                .map(|w| -> Result<(BasicPrefix, Url), ControllerError> {
+                    saved_witnesses
+                        .get(&w.to_str())
+                        .map(|i| (w.clone(), i.clone()))
+                        .ok_or(ControllerError::MissingIp(w.clone()))
+                })
+                .partition(Result::is_ok)
+        };
 
         let adresses_from_resolver = try_join_all(
             missing_ips
@@ -99,232 +924,882 @@ impl Controller {
                         None
                     }
                 })
-                .map(|ip|
-            // ask resolver about ip
-            Self::get_witness_ip(&self.resolver_addresses, ip)),
+                .map(|ip| async move {
+                    // ask resolver about ip
+                    let location = self
+                        .get_witness_ip(ip)
+                        .await
+                        .context(ControllerError::WitnessResolution(ip.clone()))?;
+                    Ok::<_, anyhow::Error>((ip.clone(), location))
+                }),
         )
         .await?;
         // Join found ips and asked ips
-        let mut witness_ips: Vec<Url> = found_ips.into_iter().map(Result::unwrap).collect();
+        let mut witness_ips: Vec<(BasicPrefix, Url)> =
+            found_ips.into_iter().map(Result::unwrap).collect();
         witness_ips.extend(adresses_from_resolver);
         Ok(witness_ips)
     }
 
+    /// Publishes event the controller generated, see `publish_stream`. The
+    /// event is kept as pending until `threshold` is met, so it's published
+    /// again on the next start if the daemon stops before that.
     async fn publish_event(
         &self,
-        event: &SignedEventData,
+        event: &[u8],
         witnesses: &[BasicPrefix],
-    ) -> Result<()> {
+        threshold: &SignatureThreshold,
+    ) -> Result<Vec<BasicPrefix>> {
+        let digest = kel_events(event)?
+            .first()
+            .map(|event| event_field(event, "d").to_string())
+            .unwrap_or_default();
+        self.pending_db
+            .insert(
+                digest.as_bytes(),
+                serde_json::to_vec(&PendingEvent {
+                    event: String::from_utf8(event.to_vec())?,
+                    witnesses: witnesses.to_vec(),
+                    threshold: threshold.clone(),
+                })?,
+            )
+            .context("Saving pending event failed")?;
+        let failed_witnesses = self.publish_stream(event, witnesses, threshold).await?;
+        self.pending_db.remove(digest.as_bytes())?;
+        Ok(failed_witnesses)
+    }
+
+    /// Publishes events again which didn't get enough receipts before the
+    /// daemon stopped, for example because it crashed while publishing.
+    /// Events which still can't be published stay pending until the next
+    /// start.
+    pub async fn republish_pending(&self) -> Result<()> {
+        for entry in self.pending_db.iter() {
+            let (digest, pending) = entry?;
+            let digest = String::from_utf8_lossy(&digest).into_owned();
+            let pending: PendingEvent = serde_json::from_slice(&pending)
+                .with_context(|| format!("Pending event {} is malformed", digest))?;
+            log::info!("Publishing pending event {} again", digest);
+            match self
+                .publish_stream(
+                    pending.event.as_bytes(),
+                    &pending.witnesses,
+                    &pending.threshold,
+                )
+                .await
+            {
+                Ok(failed_witnesses) => {
+                    report_failed_witnesses("pending", &failed_witnesses);
+                    self.pending_db.remove(digest.as_bytes())?;
+                }
+                Err(e) => log::warn!("Publishing pending event {} failed: {:#}", digest, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends event to witnesses and collects their receipts. Publishing
+    /// succeeds when enough witnesses to satisfy `threshold` responded.
+    /// Witnesses that haven't responded by then keep getting the event in a
+    /// background task, which also processes their late receipts and sends
+    /// them to all witnesses. Returns witnesses that failed to respond, so
+    /// they can be retried later.
+    #[tracing::instrument(skip_all, fields(prefix = %self.get_prefix().to_str()))]
+    async fn publish_stream(
+        &self,
+        event: &[u8],
+        witnesses: &[BasicPrefix],
+        threshold: &SignatureThreshold,
+    ) -> Result<Vec<BasicPrefix>> {
         let witness_ips = self
             .get_ips(witnesses)
             .await
             .context("Looking up witness IP address failed")?;
-        println!(
-            "\ngot witness adresses: {:?}",
+        log::debug!(
+            "Got witness addresses: {:?}",
             witness_ips
                 .iter()
-                .map(|w| w.to_string())
+                .map(|(_, ip)| ip.to_string())
                 .collect::<Vec<_>>()
         );
 
-        /// Helper struct for deserializing data provided by witnesses
-        #[derive(Serialize, Deserialize)]
-        struct RespondData {
-            parsed: u64,
-            not_parsed: String,
-            receipts: Vec<String>,
-            errors: Vec<String>,
-        }
-
-        // send event to witnesses and collect receipts
-        let client = reqwest::Client::new();
-        let witness_receipts = try_join_all(witness_ips.iter().map(|ip| {
-            client
-                .post(&format!("{}publish", ip))
-                .body(String::from_utf8(event.to_cesr().unwrap()).unwrap())
-                .send()
-        }))
-        .await
-        .context("Publishing event to witness failed")?
-        .into_iter()
-        .map(|r| r.json::<RespondData>());
-
-        let witness_receipts = try_join_all(witness_receipts)
-            .await
-            .unwrap()
-            .iter()
-            .map(|r| r.receipts.join(""))
-            .collect::<Vec<_>>();
-
-        println!("\ngot {} witness receipts...", witness_receipts.len());
-
-        // process receipts and send them to all of the witnesses
-        let _processing = witness_receipts
-            .iter()
-            .map(|rct| -> Result<_> {
-                self.controller
-                    .parse_and_process(rct.as_bytes())
-                    .map_err(|e| anyhow::anyhow!(e.to_string()))
-            })
-            .collect::<Result<Vec<_>>>()
-            .context("Processing witness receipts failed")?;
-
-        try_join_all(witness_ips.iter().map(|ip| {
-            client
-                .post(&format!("{}publish", ip))
-                .body(witness_receipts.join(""))
-                .send()
-        }))
-        .await
-        .context("Publishing witness receipts failed")?;
-        Ok(())
-    }
+        log::debug!("Publishing event: {}", String::from_utf8_lossy(event));
 
-    pub fn save_witness_data(
-        &mut self,
-        witness_config: &[WitnessConfig],
-    ) -> Result<Vec<BasicPrefix>> {
-        // save witnesses location, because they can not be find in resolvers
-        witness_config
+        // send event to witnesses and process every receipt as soon as it
+        // arrives, keep the valid ones to send them to all of the witnesses.
+        // Publishing futures own everything they use, so the ones still
+        // running once the threshold is met can be moved to a task.
+        let event: Arc<[u8]> = Arc::from(event);
+        let witness_timeout = self.network_config.witness_timeout;
+        let publishing = witness_ips
             .iter()
-            .map(|w| {
-                if let Ok(loc) = w.get_location() {
-                    self.saved_witnesses
-                        .insert(w.get_aid().unwrap().to_str(), loc);
-                } else {
-                    // TODO check if resolver got it id?
-                };
-                w.get_aid()
+            .map(|(witness, ip)| {
+                let transport = self.transport_for(witness);
+                let (witness, ip, event) = (witness.clone(), ip.clone(), Arc::clone(&event));
+                let span = tracing::info_span!(
+                    "witness_publish",
+                    witness = %witness.to_str(),
+                    location = %ip
+                );
+                async move {
+                    let response =
+                        tokio::time::timeout(witness_timeout, transport.publish(&ip, &event))
+                            .await
+                            .unwrap_or_else(|_| {
+                                Err(anyhow::anyhow!("Witness {} didn't respond in time", ip))
+                            });
+                    (witness, ip, response)
+                }
+                .instrument(span)
             })
-            .collect::<Result<Vec<_>>>()
-    }
+            .collect::<Vec<_>>();
+        let concurrency = self.witness_concurrency(witness_ips.len());
+        let mut responses = stream::iter(publishing).buffer_unordered(concurrency);
+        let mut witness_receipts = vec![];
+        let mut receipted_witnesses = vec![];
+        let mut failed_witnesses = vec![];
+        let mut witness_errors = vec![];
+        while let Some((witness, ip, response)) = responses.next().await {
+            match process_witness_response(&self.controller, &ip, response) {
+                Ok(receipt) => {
+                    Metrics::increment(&self.metrics.witness_publish_successes);
+                    witness_receipts.push(receipt);
+                    receipted_witnesses.push(witness);
+                }
+                Err(e) => {
+                    log::warn!("{:#}", e);
+                    Metrics::increment(&self.metrics.witness_publish_failures);
+                    failed_witnesses.push(witness);
+                    witness_errors.push(format!("{:#}", e));
+                }
+            }
+            // Don't wait for slow witnesses once the threshold is met.
+            if is_threshold_met(threshold, witnesses, &receipted_witnesses) {
+                break;
+            }
+        }
 
-    pub async fn rotate(
-        &mut self,
-        witness_list: Option<Vec<WitnessConfig>>,
-        witness_threshold: Option<u64>,
-    ) -> Result<()> {
-        let (old_witnesses, old_threshold) = {
-            let old_witnesses_config = self
-                .get_state()?
-                .ok_or(anyhow::anyhow!("There's no state in database"))?
-                .witness_config;
-            (old_witnesses_config.witnesses, old_witnesses_config.tally)
-        };
+        log::debug!("Got {} witness receipts", witness_receipts.len());
 
-        // Check threshold
-        let new_threshold = match (witness_list.as_ref(), witness_threshold) {
-            (None, None) => Ok(old_threshold),
-            (None, Some(t)) => {
-                if old_witnesses.len() > t as usize {
-                    Err(anyhow::anyhow!("Improper thrreshold"))
-                } else {
-                    Ok(SignatureThreshold::Simple(t))
-                }
+        if !is_threshold_met(threshold, witnesses, &receipted_witnesses) {
+            return Err(ControllerError::PublishFailed {
+                received: witness_receipts.len(),
+                required: required_receipts(threshold, witnesses.len()),
+                failed_witnesses,
+                errors: witness_errors,
             }
-            (Some(wits), None) => {
-                if let SignatureThreshold::Simple(t) = old_threshold {
-                    if t > wits.len() as u64 {
-                        Err(anyhow::anyhow!("Improper threshold"))
-                    } else {
-                        Ok(old_threshold)
+            .into());
+        }
+
+        // Witnesses that answered get receipts now, the ones still publishing
+        // get them from the task together with the late receipts.
+        let (answered_ips, pending_ips): (Vec<_>, Vec<_>) =
+            witness_ips.iter().cloned().partition(|(witness, _)| {
+                receipted_witnesses.contains(witness) || failed_witnesses.contains(witness)
+            });
+        if !pending_ips.is_empty() {
+            log::debug!(
+                "Not waiting for receipts of witnesses: {:?}",
+                pending_ips
+                    .iter()
+                    .map(|(witness, _)| witness.to_str())
+                    .collect::<Vec<_>>()
+            );
+            let witness_transports = witness_ips
+                .iter()
+                .map(|(witness, ip)| (self.transport_for(witness), ip.clone()))
+                .collect::<Vec<_>>();
+            // Weak, so closing the controller still closes the key event
+            // database. Late receipts are dropped once it's closed.
+            let (keri, metrics) = (Arc::downgrade(&self.controller), self.metrics.clone());
+            let mut witness_receipts = witness_receipts.clone();
+            tokio::spawn(
+                async move {
+                    let received = witness_receipts.len();
+                    while let Some((_, ip, response)) = responses.next().await {
+                        let keri = match keri.upgrade() {
+                            Some(keri) => keri,
+                            None => return,
+                        };
+                        match process_witness_response(&keri, &ip, response) {
+                            Ok(receipt) => {
+                                Metrics::increment(&metrics.witness_publish_successes);
+                                witness_receipts.push(receipt);
+                            }
+                            Err(e) => {
+                                log::warn!("{:#}", e);
+                                Metrics::increment(&metrics.witness_publish_failures);
+                            }
+                        }
+                    }
+                    if witness_receipts.len() == received {
+                        return;
+                    }
+                    let witness_receipts = witness_receipts.join("");
+                    for (transport, ip) in witness_transports {
+                        if let Err(e) = transport.send(&ip, witness_receipts.as_bytes()).await {
+                            log::warn!("Publishing witness receipts to {} failed: {:#}", ip, e);
+                        }
                     }
-                } else {
-                    Err(anyhow::anyhow!("Improper threshold"))
                 }
+                .in_current_span(),
+            );
+        }
+
+        let witness_receipts = witness_receipts.join("");
+        let receipts_sending_results = stream::iter(answered_ips.iter().map(|(witness, ip)| {
+            let witness_receipts = &witness_receipts;
+            async move {
+                self.transport_for(witness)
+                    .send(ip, witness_receipts.as_bytes())
+                    .await
+                    .with_context(|| format!("Publishing witness receipts to {} failed", ip))
             }
-            (Some(wits), Some(t)) => {
-                if t > wits.len() as u64 {
-                    Err(anyhow::anyhow!("Improper threshold"))
-                } else {
-                    Ok(SignatureThreshold::Simple(t))
+        }))
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+        for ((witness, _), result) in answered_ips.iter().zip(receipts_sending_results) {
+            if let Err(e) = result {
+                log::warn!("{:#}", e);
+                if !failed_witnesses.contains(witness) {
+                    failed_witnesses.push(witness.clone());
                 }
             }
-        }?;
+        }
 
-        let (witness_to_add, witness_to_remove) = match witness_list {
-            Some(ref new_wits) => {
-                let new_witness_prefixes = new_wits
-                    .iter()
-                    .map(|conf| conf.get_aid().unwrap())
-                    .collect::<Vec<_>>();
-                (
-                    Some(
-                        new_witness_prefixes
-                            .clone()
-                            .into_iter()
-                            .filter(|w| !old_witnesses.contains(w))
-                            .collect::<Vec<_>>(),
-                    ),
-                    Some(
-                        old_witnesses
-                            .clone()
-                            .into_iter()
-                            .filter(|w| !new_witness_prefixes.contains(w))
-                            .collect::<Vec<BasicPrefix>>(),
-                    ),
-                )
-            }
-            None => (None, None),
-        };
+        Ok(failed_witnesses)
+    }
 
-        let wits_prefs = self.save_witness_data(&witness_list.unwrap_or_default())?;
+    /// Returns transport used to reach `witness`.
+    fn transport_for(&self, witness: &BasicPrefix) -> Arc<dyn WitnessTransport> {
+        self.mailbox_transports
+            .lock()
+            .ok()
+            .and_then(|transports| transports.get(&witness.to_str()).cloned())
+            .unwrap_or_else(|| self.transport.clone())
+    }
 
-        // Get new witnesses address and kerl
-        let new_ips = self.get_ips(&witness_to_add.as_ref().unwrap()).await?;
+    /// Remembers that `witness` leaves receipts in the mailbox at
+    /// `mailbox_path` under its location.
+    fn save_witness_mailbox(&self, witness: &BasicPrefix, mailbox_path: &str) -> Result<()> {
+        self.mailbox_db
+            .insert(witness.to_str().as_bytes(), mailbox_path.as_bytes())?;
+        self.mailbox_transports
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .insert(
+                witness.to_str(),
+                Arc::new(MailboxTransport::new(
+                    self.client.clone(),
+                    self.network_config.clone(),
+                    mailbox_path.to_string(),
+                )),
+            );
+        Ok(())
+    }
 
-        let kerl: Vec<u8> = [self.get_kel()?.as_bytes(), &self.get_receipts()?].concat();
+    fn save_witness_location(&self, witness: &BasicPrefix, location: Url) -> Result<()> {
+        let location = base_url(location);
+        self.witness_db
+            .insert(witness.to_str().as_bytes(), location.as_str().as_bytes())?;
+        self.saved_witnesses
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .insert(witness.to_str(), location);
+        Ok(())
+    }
 
-        // Send kerl and witness receipts to the new witnesses
-        let client = reqwest::Client::new();
-        let _kel_sending_results = for ip in new_ips {
-            client
-                .post(&format!("{}publish", ip))
-                .body(String::from_utf8(kerl.clone()).unwrap())
-                .send()
-                .await?;
+    /// Fetches OOBI (`{location}/oobi/{cid}/witness/{eid}`), processes the
+    /// key event log and endpoint events it returns and remembers the
+    /// introduced witness location. Returns state of the introduced
+    /// identifier, if it's known afterwards.
+    pub async fn resolve_oobi(&self, oobi: &Url) -> Result<Option<IdentifierState>> {
+        let segments = oobi
+            .path_segments()
+            .map(|segments| segments.collect::<Vec<_>>())
+            .unwrap_or_default();
+        let (cid, witness) = match segments.as_slice() {
+            ["oobi", cid, "witness", eid, ..] => (parse_prefix(cid)?, Some(eid)),
+            ["oobi", cid, ..] => (parse_prefix(cid)?, None),
+            _ => return Err(ControllerError::Parse(format!("{} is not an OOBI url", oobi)).into()),
         };
 
-        let rotation_event = self.controller.rotate(
-            witness_to_add.as_deref(),
-            witness_to_remove.as_deref(),
-            Some(new_threshold),
-        )?;
+        let stream = self
+            .client
+            .get(oobi.clone())
+            .send()
+            .await
+            .with_context(|| format!("Fetching OOBI {} failed", oobi))?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        self.controller
+            .parse_and_process(&stream)
+            .map_err(|e| ControllerError::Parse(e.to_string()))
+            .context("Can't process OOBI stream")?;
 
-        println!(
-            "\nRotation event:\n{}",
-            String::from_utf8(rotation_event.serialize()?)?
-        );
+        if let Some(witness) = witness {
+            let location = oobi.join("/")?;
+            self.save_witness_location(&parse_prefix(witness)?, location)?;
+        }
 
-        self.publish_event(
-            &SignedEventData::from(&rotation_event),
-            &if wits_prefs.is_empty() {
-                old_witnesses
-            } else {
+        Ok(self.controller.get_state_for_prefix(&cid)?)
+    }
+
+    /// Processes key event log of another identifier, so its attestations
+    /// can be verified without asking resolvers. Returns the identifier's
+    /// resulting state.
+    pub fn import_kel(&self, kel: &[u8]) -> Result<IdentifierState> {
+        let (prefix, last_sn) = check_kel_sequence(kel)?;
+        self.controller
+            .parse_and_process(kel)
+            .map_err(|e| ControllerError::Parse(e.to_string()))
+            .context("Can't process key event log")?;
+
+        // Events with invalid signatures are dropped while processing.
+        match self.controller.get_state_for_prefix(&prefix)? {
+            Some(state) if state.sn >= last_sn => Ok(state),
+            _ => Err(ControllerError::Parse(format!(
+                "Events of {} weren't accepted, their signatures are invalid",
+                prefix.to_str()
+            ))
+            .into()),
+        }
+    }
+
+    /// Returns OOBIs of the controller's identifier, one for each current
+    /// witness with known location.
+    pub async fn get_oobis(&self) -> Result<Vec<WitnessOobi>> {
+        let witnesses = self
+            .get_state()?
+            .ok_or(ControllerError::NotIncepted)?
+            .witness_config
+            .witnesses;
+        let cid = self.get_prefix().to_str();
+
+        let mut oobis = vec![];
+        for witness in witnesses {
+            let location = match self.get_ips(std::slice::from_ref(&witness)).await {
+                Ok(ips) => match ips.into_iter().next() {
+                    Some((_, location)) => location,
+                    None => continue,
+                },
+                Err(e) => {
+                    log::warn!("Witness {} left out of OOBIs: {:#}", witness.to_str(), e);
+                    continue;
+                }
+            };
+            let eid = witness.to_str();
+            oobis.push(WitnessOobi {
+                oobi: location.join(&format!("oobi/{}/witness/{}", cid, eid))?,
+                eid,
+                role: "witness",
+                url: location,
+            });
+        }
+        Ok(oobis)
+    }
+
+    /// Resolves locations of current witnesses and checks whether they
+    /// respond at all.
+    pub async fn check_witnesses(&self) -> Result<Vec<WitnessStatus>> {
+        let witnesses = self
+            .get_state()?
+            .map(|state| state.witness_config.witnesses)
+            .unwrap_or_default();
+
+        Ok(join_all(witnesses.iter().map(|w| async move {
+            let location = self
+                .get_ips(std::slice::from_ref(w))
+                .await
+                .ok()
+                .and_then(|ips| ips.into_iter().next())
+                .map(|(_, ip)| ip);
+            let reachable = match &location {
+                Some(ip) => self.client.get(ip.clone()).send().await.is_ok(),
+                None => false,
+            };
+            WitnessStatus {
+                aid: w.to_str(),
+                location,
+                reachable,
+            }
+        }))
+        .await)
+    }
+
+    /// Finds configured witness identifier and location, the way inception
+    /// would, and checks the witness responds there. Nothing is saved.
+    pub async fn probe_witness(&self, witness: &WitnessConfig) -> Result<(BasicPrefix, Url)> {
+        let aid = witness.get_aid(&self.client).await?;
+        let location = match witness.get_location() {
+            Ok(location) => location,
+            Err(_) => self
+                .get_witness_ip(&aid)
+                .await
+                .context(ControllerError::WitnessResolution(aid.clone()))?,
+        };
+        self.client
+            .get(location.clone())
+            .send()
+            .await
+            .with_context(|| format!("Witness {} doesn't respond at {}", aid.to_str(), location))?;
+        Ok((aid, location))
+    }
+
+    /// Checks every configured resolver responds at its base URL. Any HTTP
+    /// response counts, resolvers don't have to serve anything there.
+    pub async fn probe_resolvers(&self) -> Vec<(Url, Result<()>)> {
+        join_all(self.resolver_addresses.iter().map(|resolver| async move {
+            let result = self
+                .client
+                .get(resolver.clone())
+                .send()
+                .await
+                .map(|_| ())
+                .with_context(|| format!("Resolver {} doesn't respond", resolver));
+            (resolver.clone(), result)
+        }))
+        .await
+    }
+
+    pub async fn save_witness_data(
+        &self,
+        witness_config: &[WitnessConfig],
+    ) -> Result<Vec<BasicPrefix>> {
+        let mut witness_prefixes = vec![];
+        for w in witness_config {
+            let aid = w.get_aid(&self.client).await?;
+            // save witnesses location, because they can not be find in resolvers
+            if let Ok(loc) = w.get_location() {
+                self.save_witness_location(&aid, loc)?;
+            } else {
+                // TODO check if resolver got it id?
+            };
+            if let Some(mailbox_path) = &w.mailbox_path {
+                self.save_witness_mailbox(&aid, mailbox_path)?;
+            }
+            witness_prefixes.push(aid);
+        }
+        Ok(witness_prefixes)
+    }
+
+    /// Validates requested witness changes and works out how they differ
+    /// from the current witness set. Nothing is saved, so the plan can be
+    /// previewed without side effects. With `expected_sn`, the rotation is
+    /// rejected unless the last event has this sequence number, so a retried
+    /// request doesn't rotate twice.
+    async fn plan_rotation(
+        &self,
+        witness_list: Option<Vec<WitnessConfig>>,
+        witness_threshold: Option<SignatureThreshold>,
+        expected_sn: Option<u64>,
+    ) -> Result<RotationPlan> {
+        let state = self.get_state()?.ok_or(ControllerError::NotIncepted)?;
+        match expected_sn {
+            Some(expected) if expected != state.sn => {
+                return Err(ControllerError::UnexpectedSn {
+                    expected,
+                    current: state.sn,
+                }
+                .into())
+            }
+            _ => (),
+        }
+        let (old_witnesses, old_threshold) = (
+            state.witness_config.witnesses.clone(),
+            state.witness_config.tally.clone(),
+        );
+
+        // Check threshold
+        let threshold = witness_threshold.unwrap_or(old_threshold);
+        validate_threshold(
+            witness_list
+                .as_ref()
+                .map(Vec::len)
+                .unwrap_or(old_witnesses.len()),
+            &threshold,
+        )?;
+
+        let mut wits_prefs = vec![];
+        for w in witness_list.as_deref().unwrap_or_default() {
+            wits_prefs.push(w.get_aid(&self.client).await?);
+        }
+
+        let (witness_to_add, witness_to_remove) = match witness_list {
+            Some(_) => {
+                let new_witness_prefixes = wits_prefs.clone();
+                (
+                    Some(
+                        new_witness_prefixes
+                            .clone()
+                            .into_iter()
+                            .filter(|w| !old_witnesses.contains(w))
+                            .collect::<Vec<_>>(),
+                    ),
+                    Some(
+                        old_witnesses
+                            .clone()
+                            .into_iter()
+                            .filter(|w| !new_witness_prefixes.contains(w))
+                            .collect::<Vec<BasicPrefix>>(),
+                    ),
+                )
+            }
+            None => (None, None),
+        };
+
+        Ok(RotationPlan {
+            state,
+            witness_config: witness_list.unwrap_or_default(),
+            witnesses: if wits_prefs.is_empty() {
+                old_witnesses
+            } else {
                 wits_prefs
             },
-        )
-        .await?;
-        println!("\nKeys rotated succesfully.");
+            witness_to_add,
+            witness_to_remove,
+            threshold,
+        })
+    }
+
+    /// Builds rotation of `plan` to the next keys of the key manager,
+    /// committing to the keys that follow them. The signing threshold set at
+    /// inception applies to both. Rotations of a delegated identifier are
+    /// delegated rotations.
+    fn build_rotation(&self, plan: &RotationPlan) -> Result<EventMessage> {
+        let (keys, next_keys) = {
+            let key_manager = self.controller.key_manager();
+            let key_manager = key_manager.lock().map_err(|_| Error::MutexPoisoned)?;
+            (key_manager.next_keys(), key_manager.following_keys())
+        };
+        let builder = match &self.delegator {
+            Some(delegator) => {
+                EventMsgBuilder::new(EventType::DelegatedRotation).with_delegator(&delegator.aid)
+            }
+            None => EventMsgBuilder::new(EventType::Rotation),
+        };
+        Ok(builder
+            .with_prefix(&self.get_prefix())
+            .with_sn(plan.state.sn + 1)
+            .with_previous_event(&plan.state.last_event_digest)
+            .with_keys(keys)
+            .with_next_keys(next_keys)
+            .with_threshold(&plan.state.current.threshold)
+            .with_next_threshold(&plan.state.current.threshold)
+            .with_witness_to_add(plan.witness_to_add.as_deref().unwrap_or_default())
+            .with_witness_to_remove(plan.witness_to_remove.as_deref().unwrap_or_default())
+            .with_witness_threshold(&plan.threshold)
+            .build()?)
+    }
+
+    /// Builds rotation event the same as `rotate` would, without signing,
+    /// publishing or rotating keys. Returns the unsigned event and state
+    /// identifier would have after it.
+    pub async fn preview_rotation(
+        &self,
+        witness_list: Option<Vec<WitnessConfig>>,
+        witness_threshold: Option<SignatureThreshold>,
+        expected_sn: Option<u64>,
+    ) -> Result<(String, IdentifierState)> {
+        let plan = self
+            .plan_rotation(witness_list, witness_threshold, expected_sn)
+            .await?;
+        let rotation_event = self.build_rotation(&plan)?;
+        let projected_state = plan.project(&rotation_event)?;
+
+        Ok((
+            String::from_utf8(rotation_event.serialize()?)?,
+            projected_state,
+        ))
+    }
+
+    /// Rotates controller keys to the ones committed in the previous
+    /// establishment event and optionally changes witnesses and witness
+    /// threshold. Returns the rotation event and state identifier has after
+    /// it.
+    pub async fn rotate(
+        &self,
+        witness_list: Option<Vec<WitnessConfig>>,
+        witness_threshold: Option<SignatureThreshold>,
+        expected_sn: Option<u64>,
+    ) -> Result<(String, IdentifierState)> {
+        let _event_guard = self.event_lock.lock().await;
+        let plan = self
+            .plan_rotation(witness_list, witness_threshold, expected_sn)
+            .await?;
+        // Rejected rotation is reported before anything is saved or sent.
+        let rotation_event = self.build_rotation(&plan)?;
+        plan.project(&rotation_event)?;
+        self.save_witness_data(&plan.witness_config).await?;
+
+        // Send kerl and witness receipts to the new witnesses, if there are any
+        let new_witnesses = plan.witness_to_add.as_deref().unwrap_or_default();
+        if !new_witnesses.is_empty() {
+            let new_ips = self
+                .get_ips(new_witnesses)
+                .await
+                .context("Can't find locations of new witnesses")?;
+            let kerl: Vec<u8> = [self.get_kel()?.as_bytes(), &self.get_receipts()?].concat();
+            for (witness, ip) in new_ips {
+                self.transport_for(&witness)
+                    .send(&ip, &kerl)
+                    .await
+                    .with_context(|| format!("Sending KEL to witness {} failed", ip))?;
+            }
+        }
+
+        let serialized_event = String::from_utf8(rotation_event.serialize()?)?;
+        log::debug!("Rotation event: {}", serialized_event);
+        let signatures = self.sign_event(serialized_event.as_bytes(), true)?;
+        let signed_event = match &self.delegator {
+            Some(delegator) => {
+                let (signed_event, delegator_kel) = self
+                    .process_delegated_event(delegator, &rotation_event, &signatures)
+                    .await
+                    .context("Delegated rotation failed")?;
+                self.send_delegator_kel(&plan.witnesses, &delegator_kel)
+                    .await;
+                signed_event
+            }
+            None => self.process_own_event(&rotation_event, &signatures)?,
+        };
+        // The KEL establishes the next keys now, the key manager has to
+        // follow before anything else is signed.
+        self.controller
+            .key_manager()
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .rotate()?;
+
+        let failed_witnesses = self
+            .publish_event(&signed_event, &plan.witnesses, &plan.threshold)
+            .await?;
+        report_failed_witnesses("rotation", &failed_witnesses);
+        let state = self.get_state()?.ok_or(ControllerError::NotIncepted)?;
+        log::info!(
+            "Keys rotated successfully, current keys: {:?}",
+            state
+                .current
+                .public_keys
+                .iter()
+                .map(|key| key.to_str())
+                .collect::<Vec<_>>()
+        );
+
+        Ok((serialized_event, state))
+    }
+
+    /// Anchors digests in the KEL with an interaction event and publishes
+    /// the event to current witnesses.
+    pub async fn interact(&self, digests: &[SelfAddressingPrefix]) -> Result<()> {
+        let _event_guard = self.event_lock.lock().await;
+        let (signed_event, state) = self.anchor(digest_seals(digests))?;
+        self.publish_interaction(&signed_event, &state).await
+    }
+
+    /// Anchors delegated inception or rotation of another identifier, sent
+    /// signed by its controller, with an interaction event and publishes it
+    /// to current witnesses. Inceptions have to name this identifier as
+    /// delegator, rotations have to be of an identifier whose inception it
+    /// anchored, and the event has to be signed by the keys it establishes.
+    pub async fn anchor_delegated(&self, stream: &[u8]) -> Result<()> {
+        let (event, serialized, signatures) = parse_signed_event(stream)?;
+        let prefix = event_field(&event, "i");
+        match event_field(&event, "t") {
+            "dip" if event_field(&event, "di") == self.get_prefix().to_str() => {}
+            "dip" => {
+                return Err(ControllerError::DelegationRejected(format!(
+                    "{} names {:?} as delegator",
+                    prefix,
+                    event_field(&event, "di")
+                ))
+                .into())
+            }
+            "drt" => {
+                let delegated = self.get_kel_events()?.iter().any(|own_event| {
+                    own_event.anchors.iter().any(|seal| {
+                        seal.get("i").and_then(serde_json::Value::as_str) == Some(prefix)
+                    })
+                });
+                if !delegated {
+                    return Err(ControllerError::DelegationRejected(format!(
+                        "{} isn't delegated by {}",
+                        prefix,
+                        self.get_prefix().to_str()
+                    ))
+                    .into());
+                }
+            }
+            other => {
+                return Err(ControllerError::DelegationRejected(format!(
+                    "{:?} isn't a delegated event",
+                    other
+                ))
+                .into())
+            }
+        }
+        check_event_signatures(&event, serialized, &signatures)?;
+
+        let sn = u64::from_str_radix(event_field(&event, "s"), 16).map_err(|_| {
+            ControllerError::Parse(format!("Invalid sequence number in event {}", event))
+        })?;
+        let seal = Seal::Event(EventSeal {
+            prefix: parse_prefix(prefix)?,
+            sn,
+            event_digest: parse_prefix(event_field(&event, "d"))?,
+        });
+        let _event_guard = self.event_lock.lock().await;
+        let (signed_event, state) = self.anchor(vec![seal])?;
+        log::info!("Anchored event {} of delegated identifier {}", sn, prefix);
+        self.publish_interaction(&signed_event, &state).await
+    }
+
+    /// Builds interaction event anchoring `seals` and processes it into the
+    /// KEL. Returns the signed event and the state it was built on, whose
+    /// witnesses it's published to. `event_lock` has to be held.
+    fn anchor(&self, seals: Vec<Seal>) -> Result<(Vec<u8>, IdentifierState)> {
+        let state = self.get_state()?.ok_or(ControllerError::NotIncepted)?;
 
+        let ixn_event = EventMsgBuilder::new(EventType::Interaction)
+            .with_prefix(&state.prefix)
+            .with_sn(state.sn + 1)
+            .with_previous_event(&state.last_event_digest)
+            .with_seal(seals)
+            .build()?;
+
+        let serialized_event = ixn_event.serialize()?;
+        log::debug!(
+            "Interaction event: {}",
+            String::from_utf8_lossy(&serialized_event)
+        );
+        let signatures = self.sign_event(&serialized_event, false)?;
+        let signed_event = self.process_own_event(&ixn_event, &signatures)?;
+        Ok((signed_event, state))
+    }
+
+    async fn publish_interaction(
+        &self,
+        signed_event: &[u8],
+        state: &IdentifierState,
+    ) -> Result<()> {
+        let failed_witnesses = self
+            .publish_event(
+                signed_event,
+                &state.witness_config.witnesses,
+                &state.witness_config.tally,
+            )
+            .await
+            .context("Publishing interaction event failed")?;
+        report_failed_witnesses("interaction", &failed_witnesses);
         Ok(())
     }
 
+    /// Revokes attestation issued by the controller. The revocation event is
+    /// saved right after its anchor is processed into the KEL, before the
+    /// anchor is published, so the TEL never has an event the KEL doesn't
+    /// anchor and a failed publish leaves both saved, with the anchor
+    /// pending until it's published again.
+    pub async fn revoke(&self, attest_hash: &str) -> Result<TelEvent> {
+        let _event_guard = self.event_lock.lock().await;
+        if self.tel.get(attest_hash)?.is_some() {
+            return Err(ControllerError::AlreadyRevoked(attest_hash.to_string()).into());
+        }
+        let (event, digest) = TelEvent::revocation(attest_hash)?;
+        let (signed_event, state) = self
+            .anchor(digest_seals(&[digest]))
+            .context("Anchoring revocation failed")?;
+        self.tel.insert(&event)?;
+        log::info!("Revoked attestation {:?}", attest_hash);
+        self.publish_interaction(&signed_event, &state)
+            .await
+            .context("Publishing revocation anchor failed")?;
+        Ok(event)
+    }
+
+    pub fn attestation_status(&self, attest_hash: &str) -> Result<AttestationStatus> {
+        self.tel.status(attest_hash)
+    }
+
+    /// Signs data with the first current key the controller holds.
     pub fn sign(&self, data: &[u8]) -> Result<AttachedSignaturePrefix, Error> {
-        Ok(AttachedSignaturePrefix::new(
-            // assume Ed signature
-            SelfSigning::Ed25519Sha512,
-            self.controller
-                .key_manager()
-                .lock()
-                .map_err(|_| Error::MutexPoisoned)?
-                .sign(data)?,
-            // asume just one key for now
-            0,
-        ))
+        self.sign_all(data)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::SemanticError("No current key to sign with".into()))
     }
 
-    pub async fn _verify(
+    /// Signs data with every current key the controller holds. Each signature
+    /// is made by the key at its index in current key config.
+    pub fn sign_all(&self, data: &[u8]) -> Result<Vec<AttachedSignaturePrefix>, Error> {
+        let controlled_keys = self.controlled_keys()?;
+        if controlled_keys.is_empty() {
+            return Err(Error::SemanticError("No current key to sign with".into()));
+        }
+        let mut signatures = self
+            .controller
+            .key_manager()
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .sign_all(data);
+        Ok(controlled_keys
+            .into_iter()
+            .map(|(position, derivation, index)| {
+                AttachedSignaturePrefix::new(
+                    derivation,
+                    std::mem::take(&mut signatures[position]),
+                    index,
+                )
+            })
+            .collect())
+    }
+
+    /// Finds key manager's keys in current key config. Returns position of
+    /// each found key in the key manager, signature derivation matching its
+    /// type and its index in key config.
+    fn controlled_keys(&self) -> Result<Vec<(usize, SelfSigning, u16)>, Error> {
+        let own_keys = self
+            .controller
+            .key_manager()
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .current_keys();
+        let current_keys = self
+            .get_state()
+            .map_err(|e| Error::SemanticError(format!("{:#}", e)))?
+            .map(|state| state.current.public_keys)
+            .unwrap_or_default();
+        Ok(own_keys
+            .iter()
+            .enumerate()
+            .filter_map(|(position, own_key)| {
+                current_keys
+                    .iter()
+                    .position(|key| key.public_key.key() == own_key.public_key.key())
+                    .map(|index| {
+                        (
+                            position,
+                            signature_derivation(&current_keys[index].derivation),
+                            index as u16,
+                        )
+                    })
+            })
+            .collect())
+    }
+
+    /// Verifies `signatures` of `message` against issuer's current keys. If
+    /// that fails, the keys are refreshed and verification is retried once.
+    /// Signatures that don't verify give `ThresholdNotMet` or
+    /// `UnknownSignature`, issuer without known keys `UnknownIssuer`, other
+    /// errors mean the keys couldn't be looked up.
+    /// Whether keys of an issuer should be fetched again when verification
+    /// with the known ones fails.
+    pub fn refreshes_stale_keys(&self) -> bool {
+        self.network_config.refresh_stale_keys
+    }
+
+    pub async fn verify(
         &self,
         issuer: &IdentifierPrefix,
         message: &[u8],
@@ -333,148 +1808,1150 @@ impl Controller {
         let key_config = self
             .get_public_keys(issuer)
             .await?
-            .ok_or(anyhow::anyhow!("Can't find issuer's keys"))?;
-        key_config.verify(message, signatures)?;
-
-        // Logic for determining the index of the signature
-        // into attached signature prefix to check signature threshold
-        // let indexed_signatures: Result<Vec<AttachedSignaturePrefix>> = signatures
-        //     .iter()
-        //     .map(|signature| {
-        //         (
-        //             key_config
-        //                 .public_keys
-        //                 .iter()
-        //                 .position(|x| x.verify(message, signature).unwrap()),
-        //             // .ok_or(napi::Error::from_reason(format!("There is no key for signature: {}", signature.to_str())).unwrap(),
-        //             signature,
-        //         )
-        //     })
-        //     .map(|(i, signature)| match i {
-        //         Some(i) => Ok(AttachedSignaturePrefix { index: i as u16, signature: signature.clone() }),
-        //         None => {
-        // 			// signature don't match any public key
-        // 			todo!()
-        // 		},
-        //     })
-        //     .collect();
+            .ok_or_else(|| ControllerError::UnknownIssuer(issuer.clone()))?;
 
-        Ok(())
+        match verify_signatures(&key_config, message, signatures) {
+            Err(e) if self.network_config.refresh_stale_keys => {
+                // The issuer may have rotated after the resolver that answered
+                // got its key event log.
+                log::info!(
+                    "Verification with keys of {} failed, refreshing them: {:#}",
+                    issuer.to_str(),
+                    e
+                );
+                match self.refresh_public_keys(issuer).await? {
+                    Some(key_config) => verify_signatures(&key_config, message, signatures),
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
     }
 
-    pub async fn get_witness_ip(resolvers: &[Url], witness: &BasicPrefix) -> Result<Url> {
+    /// Fetches issuer's key event log from every resolver, not only the
+    /// first one that answers, and processes all of them, so the local state
+    /// catches up with the most recent one. Drops cached key state and keys
+    /// of the issuer.
+    pub async fn refresh_public_keys(
+        &self,
+        issuer: &IdentifierPrefix,
+    ) -> Result<Option<KeyConfig>> {
+        self.resolved_states
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .remove(&issuer.to_str());
+        self.resolved_keys
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .remove(&issuer.to_str());
+
+        let path = format!("key_logs/{}", issuer.to_str());
+        let kels = join_all(self.resolver_addresses.iter().map(|resolver| {
+            let path = &path;
+            async move {
+                let kel = self
+                    .client
+                    .get(resolver.join(path)?)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                check_kel_prefix(&kel, issuer)?;
+                Ok::<_, anyhow::Error>(kel)
+            }
+        }))
+        .await;
+        for kel in kels {
+            let processed = kel.and_then(|kel| {
+                self.controller
+                    .parse_and_process(&kel)
+                    .map_err(|e| ControllerError::Parse(e.to_string()).into())
+            });
+            if let Err(e) = processed {
+                log::warn!(
+                    "Refreshing key event log of {} failed: {:#}",
+                    issuer.to_str(),
+                    e
+                );
+            }
+        }
+
+        Ok(self
+            .controller
+            .get_state_for_prefix(issuer)?
+            .map(|state| state.current))
+    }
+
+    pub async fn get_witness_ip(&self, witness: &BasicPrefix) -> Result<Url> {
         #[derive(Serialize, Clone, Deserialize)]
         struct Ip {
             pub ip: String,
         }
 
-        let witness_ip = join_all(
-            try_join_all(
-                resolvers
-                    .to_vec()
-                    .into_iter()
-                    .map(|ip| reqwest::get(format!("{}witness_ips/{}", ip, witness.to_str()))),
-            )
-            .await?
-            .into_iter()
-            .map(|r| r.json::<Ip>()),
-        )
-        .await
-        .into_iter()
-        .find(|ip| ip.is_ok());
+        if let Some((ip, resolved_at)) = self
+            .resolved_witnesses
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .get(&witness.to_str())
+        {
+            if resolved_at.elapsed() < self.network_config.witness_ip_ttl {
+                return Ok(ip.clone());
+            }
+        }
 
-        Ok(Url::parse(&format!(
-            "http://{}",
-            witness_ip
-                .expect("No such witness in registered resolvers")
-                .unwrap()
-                .ip
-        ))?)
+        // Every resolver is asked before giving up, so a single failing one
+        // doesn't hide the location known to another.
+        let ip = self
+            .query_resolvers(&format!("witness_ips/{}", witness.to_str()), |body| {
+                serde_json::from_slice::<Ip>(body)
+            })
+            .await
+            .with_context(|| {
+                format!(
+                    "No resolver could provide location of witness {}",
+                    witness.to_str()
+                )
+            })?;
+        let ip = Url::parse(&format!("http://{}", ip.ip))
+            .map(base_url)
+            .with_context(|| {
+                format!(
+                    "Resolver returned invalid location {:?} of witness {}",
+                    ip.ip,
+                    witness.to_str()
+                )
+            })?;
+        self.resolved_witnesses
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .insert(witness.to_str(), (ip.clone(), Instant::now()));
+        Ok(ip)
     }
 
     pub async fn get_state_from_resolvers(
         &self,
         prefix: &IdentifierPrefix,
     ) -> Result<IdentifierState> {
-        let state = join_all(
-            try_join_all(
-                self.resolver_addresses
-                    .to_vec()
-                    .into_iter()
-                    .map(|ip| reqwest::get(format!("{}key_states/{}", ip, prefix.to_str()))),
-            )
-            .await?
-            .into_iter()
-            .map(|r| r.json::<IdentifierState>()),
-        )
-        .await
-        .into_iter()
-        .find(|state| state.is_ok());
+        if let Some((state, resolved_at)) = self
+            .resolved_states
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .get(&prefix.to_str())
+        {
+            if resolved_at.elapsed() < self.network_config.key_state_ttl {
+                return Ok(state.clone());
+            }
+        }
 
-        state
-            .ok_or(anyhow::anyhow!(""))?
-            .map_err(|e| anyhow::anyhow!(e.to_string()))
+        let state = self
+            .query_resolvers(&format!("key_states/{}", prefix.to_str()), |body| {
+                serde_json::from_slice::<IdentifierState>(body)
+            })
+            .await?;
+        self.resolved_states
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .insert(prefix.to_str(), (state.clone(), Instant::now()));
+        Ok(state)
     }
 
+    #[tracing::instrument(skip_all, fields(issuer = %issuer.to_str()))]
     pub async fn get_public_keys(&self, issuer: &IdentifierPrefix) -> Result<Option<KeyConfig>> {
-        let log = join_all(
-            try_join_all(
-                self.resolver_addresses
-                    .to_vec()
-                    .into_iter()
-                    .map(|ip| reqwest::get(format!("{}key_logs/{}", ip, issuer.to_str()))),
-            )
-            .await?
-            .into_iter()
-            .map(|r| r.bytes()),
-        )
-        .await
-        .into_iter()
-        .filter_map(Result::ok)
-        .next();
+        if let Some((keys, resolved_at)) = self
+            .resolved_keys
+            .lock()
+            .map_err(|_| Error::MutexPoisoned)?
+            .get(&issuer.to_str())
+        {
+            if resolved_at.elapsed() < self.network_config.key_state_ttl {
+                return Ok(Some(keys.clone()));
+            }
+        }
 
-        let log = match log {
-            Some(log) => log,
-            None => return Ok(None),
+        let log = match self
+            .query_resolvers(&format!("key_logs/{}", issuer.to_str()), |body| {
+                check_kel_prefix(body, issuer)?;
+                Ok::<_, anyhow::Error>(body.to_vec())
+            })
+            .await
+        {
+            Ok(log) => log,
+            // Keys may still be known from an imported key event log.
+            Err(_) => {
+                return Ok(self
+                    .controller
+                    .get_state_for_prefix(issuer)?
+                    .map(|state| state.current))
+            }
         };
 
         self.controller
             .parse_and_process(&log)
+            .map_err(|e| ControllerError::Parse(e.to_string()))
             .context("Can't parse key event log")?;
 
-        match self.controller.get_state_for_prefix(issuer)? {
-            Some(state) => Ok(Some(state.current)),
-            None => Ok(None),
+        let keys = self
+            .controller
+            .get_state_for_prefix(issuer)?
+            .map(|state| state.current);
+        if let Some(keys) = &keys {
+            if !self.network_config.key_state_ttl.is_zero() {
+                self.resolved_keys
+                    .lock()
+                    .map_err(|_| Error::MutexPoisoned)?
+                    .insert(issuer.to_str(), (keys.clone(), Instant::now()));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Asks resolvers about `path` one by one, in configured order, until one
+    /// of them answers with a body `read` accepts. Resolvers that failed
+    /// repeatedly are asked last.
+    async fn query_resolvers<T, E, F>(&self, path: &str, read: F) -> Result<T>
+    where
+        F: Fn(&[u8]) -> Result<T, E>,
+        E: Into<anyhow::Error>,
+    {
+        let mut resolvers = self.resolver_addresses.clone();
+        {
+            let health = self
+                .resolver_health
+                .lock()
+                .map_err(|_| Error::MutexPoisoned)?;
+            // Stable sort keeps the configured order within both groups.
+            resolvers.sort_by_key(|resolver| {
+                health
+                    .get(resolver)
+                    .map(ResolverHealth::is_deprioritized)
+                    .unwrap_or(false)
+            });
         }
+
+        let mut last_error = anyhow::anyhow!("No resolvers configured");
+        for resolver in resolvers {
+            let started = Instant::now();
+            let response = async {
+                let response = self
+                    .client
+                    .get(resolver.join(path)?)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                log::debug!(
+                    "Resolver {} answered {}: {}",
+                    resolver,
+                    path,
+                    String::from_utf8_lossy(&response)
+                );
+                read(&response).map_err(Into::<anyhow::Error>::into)
+            }
+            .instrument(tracing::info_span!("resolver_query", %resolver, path))
+            .await;
+            self.metrics.observe_resolver_latency(started.elapsed());
+
+            let mut health = self
+                .resolver_health
+                .lock()
+                .map_err(|_| Error::MutexPoisoned)?;
+            let resolver_health = health.entry(resolver.clone()).or_default();
+            match response {
+                Ok(response) => {
+                    *resolver_health = ResolverHealth::default();
+                    return Ok(response);
+                }
+                Err(e) => {
+                    resolver_health.failures += 1;
+                    resolver_health.last_failure = Some(Instant::now());
+                    last_error = e.context(format!("Querying resolver {} failed", resolver));
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Writes pending witness and TEL data to disk. `Keri` doesn't give a
+    /// way to flush the key event database while it's open, see `close`.
+    pub fn flush(&self) -> Result<()> {
+        self.witness_db
+            .flush()
+            .context("Flushing witness database failed")?;
+        self.tel.flush()?;
+        Ok(())
+    }
+
+    /// Flushes controller data and closes its databases. Sled writes what's
+    /// left of the key event database when it's dropped here.
+    pub fn close(self) -> Result<()> {
+        self.flush()?;
+        drop(self);
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
     }
 
     pub fn get_prefix(&self) -> IdentifierPrefix {
-        self.controller.prefix().clone()
+        self.prefix.clone().unwrap_or_default()
     }
 
     pub fn get_kel(&self) -> Result<String> {
-        Ok(self
-            .controller
-            .get_kerl()?
-            .map(|kel| String::from_utf8(kel).expect("kel can't be converted to string"))
+        let kel = match &self.prefix {
+            Some(prefix) => EventProcessor::new(self.controller.db()).get_kerl(prefix)?,
+            None => None,
+        };
+        Ok(kel
+            .map(String::from_utf8)
+            .transpose()
+            .context("Key event log isn't valid UTF-8")?
             .unwrap_or_default())
     }
 
+    /// Returns events of the controller's KEL in order, receipts left out.
+    pub fn get_kel_events(&self) -> Result<Vec<KelEventSummary>> {
+        let kel = self.get_kel()?;
+        kel_events(kel.as_bytes())?
+            .iter()
+            .filter(|event| event_field(event, "t") != "rct")
+            .map(|event| {
+                let sn = u64::from_str_radix(event_field(event, "s"), 16).map_err(|_| {
+                    ControllerError::Parse(format!("Invalid sequence number in event {}", event))
+                })?;
+                let witnesses_added = match event_field(event, "t") {
+                    "icp" | "dip" => event_list(event, "b"),
+                    _ => event_list(event, "ba"),
+                };
+                Ok(KelEventSummary {
+                    event_type: event_field(event, "t").into(),
+                    sn,
+                    digest: event_field(event, "d").into(),
+                    keys: event_list(event, "k"),
+                    next_keys: event_list(event, "n"),
+                    witness_threshold: event.get("bt").cloned(),
+                    witnesses_added,
+                    witnesses_removed: event_list(event, "br"),
+                    anchors: event
+                        .get("a")
+                        .and_then(serde_json::Value::as_array)
+                        .cloned()
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
     pub fn get_state(&self) -> Result<Option<IdentifierState>> {
-        Ok(self.controller.get_state()?)
+        Ok(match &self.prefix {
+            Some(prefix) => self.controller.get_state_for_prefix(prefix)?,
+            None => None,
+        })
     }
 
+    /// Returns nontransferable receipts of the controller's events. No
+    /// receipts collected yet is a normal state, it gives an empty stream.
     pub fn get_receipts(&self) -> Result<Vec<u8>> {
-        Ok(self
-            .controller
-            .db()
-            .get_receipts_nt(self.controller.prefix())
-            .ok_or(anyhow::anyhow!("There are no nontransferable receipts"))?
-            .map(|r| {
-                let sed: SignedEventData = r.into();
-                sed.to_cesr().expect("CESR format problem")
+        let receipts = match self.controller.db().get_receipts_nt(&self.get_prefix()) {
+            Some(receipts) => receipts,
+            None => return Ok(vec![]),
+        };
+        let mut stream = vec![];
+        for receipt in receipts {
+            stream.extend(SignedEventData::from(receipt).to_cesr()?);
+        }
+        Ok(stream)
+    }
+
+    /// Returns witnesses which receipted the controller's event at `sn`, each
+    /// listed once.
+    pub fn get_event_receipts(&self, sn: u64) -> Result<Vec<BasicPrefix>> {
+        let receipts = match self.controller.db().get_receipts_nt(&self.get_prefix()) {
+            Some(receipts) => receipts,
+            None => return Ok(vec![]),
+        };
+        let mut witnesses = vec![];
+        for receipt in receipts.filter(|r| r.body.event.sn == sn) {
+            for (witness, _signature) in receipt.couplets {
+                if !witnesses.contains(&witness) {
+                    witnesses.push(witness);
+                }
+            }
+        }
+        Ok(witnesses)
+    }
+}
+
+/// Checks that `threshold` can be met by receipts of `witnesses` witnesses,
+/// and that it requires any receipts at all if there are witnesses.
+pub fn validate_threshold(
+    witnesses: usize,
+    threshold: &SignatureThreshold,
+) -> Result<(), ControllerError> {
+    if let SignatureThreshold::Simple(0) = threshold {
+        return match witnesses {
+            0 => Ok(()),
+            _ => Err(ControllerError::ZeroThreshold(witnesses)),
+        };
+    }
+    let all_witnesses = (0..witnesses).collect::<Vec<_>>();
+    match threshold.enough_signatures(&all_witnesses) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ControllerError::ThresholdExceedsWitnesses {
+            threshold: threshold.clone(),
+            witnesses,
+        }),
+        // Weighted threshold with a different number of weights than witnesses.
+        Err(_) => Err(ControllerError::ImproperThreshold {
+            threshold: threshold.clone(),
+            witnesses,
+        }),
+    }
+}
+
+/// Checks that signing `threshold` requires at least one signature and can
+/// be met by signatures of `keys` keys.
+pub fn validate_key_threshold(
+    keys: usize,
+    threshold: &SignatureThreshold,
+) -> Result<(), ControllerError> {
+    let all_keys = (0..keys).collect::<Vec<_>>();
+    let met = match threshold {
+        SignatureThreshold::Simple(0) => false,
+        // Weighted threshold with a different number of weights than keys
+        // can't be met either.
+        _ => threshold.enough_signatures(&all_keys).unwrap_or(false),
+    };
+    if met {
+        Ok(())
+    } else {
+        Err(ControllerError::ImproperKeyThreshold {
+            threshold: threshold.clone(),
+            keys,
+        })
+    }
+}
+
+/// Checks `signatures` of `message` against `key_config`. Signatures are
+/// indexed by the key that made them, so the signature threshold can be
+/// checked.
+fn verify_signatures(
+    key_config: &KeyConfig,
+    message: &[u8],
+    signatures: &[AttachedSignaturePrefix],
+) -> Result<()> {
+    let indexed_signatures = signatures
+        .iter()
+        .map(|signature| -> Result<AttachedSignaturePrefix> {
+            let index = key_config
+                .public_keys
+                .iter()
+                .position(|key| key.verify(message, &signature.signature).unwrap_or(false))
+                // signature don't match any public key
+                .ok_or_else(|| ControllerError::UnknownSignature(signature.to_str()))?;
+            Ok(AttachedSignaturePrefix {
+                index: index as u16,
+                signature: signature.signature.clone(),
             })
-            .flatten()
-            .collect::<Vec<_>>())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if key_config.verify(message, &indexed_signatures)? {
+        Ok(())
+    } else {
+        Err(ControllerError::ThresholdNotMet.into())
+    }
+}
+
+/// Opens sled database. Sled locks its files, so failing to get the lock
+/// means another daemon instance uses the database. Sled reports it only as
+/// an IO error with a message.
+pub fn open_sled(path: &Path) -> Result<sled::Db> {
+    sled::open(path).map_err(|e| match e {
+        sled::Error::Io(ref io)
+            if io.kind() == std::io::ErrorKind::WouldBlock
+                || io.to_string().contains("could not acquire lock") =>
+        {
+            ControllerError::DatabaseInUse(path.to_path_buf()).into()
+        }
+        e => anyhow::Error::new(e).context(format!("Opening database at {:?} failed", path)),
+    })
+}
+
+/// Returns the number of witness receipts needed to satisfy `threshold`.
+/// For weighted thresholds it depends on which witnesses receipted, so it's
+/// only used in error messages.
+pub fn required_receipts(threshold: &SignatureThreshold, witness_count: usize) -> usize {
+    match threshold {
+        SignatureThreshold::Simple(t) => *t as usize,
+        _ => witness_count,
+    }
+}
+
+/// Checks whether receipts of `receipted` witnesses satisfy `threshold` of
+/// the `witnesses` set. Weights of weighted thresholds are ordered like the
+/// witness set.
+pub fn is_threshold_met(
+    threshold: &SignatureThreshold,
+    witnesses: &[BasicPrefix],
+    receipted: &[BasicPrefix],
+) -> bool {
+    let indexes = witnesses
+        .iter()
+        .enumerate()
+        .filter(|(_, witness)| receipted.contains(witness))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    threshold.enough_signatures(&indexes).unwrap_or(false)
+}
+
+/// Checks that every event in KEL stream belongs to `prefix`, so a resolver
+/// can't slip in events of other identifiers.
+fn check_kel_prefix(kel: &[u8], prefix: &IdentifierPrefix) -> Result<()> {
+    let events = kel_events(kel)?;
+    for event in &events {
+        let event_prefix = event_field(event, "i");
+        if event_prefix != prefix.to_str() {
+            return Err(ControllerError::Parse(format!(
+                "Key event log of {} contains event of {:?}",
+                prefix.to_str(),
+                event_prefix
+            ))
+            .into());
+        }
+    }
+    if events.is_empty() {
+        return Err(ControllerError::Parse(format!(
+            "Key event log of {} is empty",
+            prefix.to_str()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks that key event log is a whole log of a single identifier: it starts
+/// with inception, sequence numbers have no gaps and receipts are only for
+/// events in the log. Returns the identifier and sequence number of its last
+/// event.
+fn check_kel_sequence(kel: &[u8]) -> Result<(IdentifierPrefix, u64), ControllerError> {
+    let events = kel_events(kel)?;
+    let prefix = parse_prefix(
+        events
+            .first()
+            .map(|event| event_field(event, "i"))
+            .ok_or_else(|| ControllerError::Parse("Key event log is empty".into()))?,
+    )?;
+    check_kel_prefix(kel, &prefix).map_err(|e| ControllerError::Parse(e.to_string()))?;
+
+    let mut next_sn = 0;
+    let mut receipted_sns = vec![];
+    for event in &events {
+        let sn = u64::from_str_radix(event_field(event, "s"), 16).map_err(|_| {
+            ControllerError::Parse(format!("Invalid sequence number in event {}", event))
+        })?;
+        match event_field(event, "t") {
+            "rct" => receipted_sns.push(sn),
+            "icp" | "dip" if next_sn != 0 => {
+                return Err(ControllerError::Parse(format!(
+                    "Key event log of {} has inception event at {}",
+                    prefix.to_str(),
+                    sn
+                )))
+            }
+            "rot" | "drt" | "ixn" if next_sn == 0 => {
+                return Err(ControllerError::Parse(format!(
+                    "Key event log of {} doesn't start with inception",
+                    prefix.to_str()
+                )))
+            }
+            _ if sn != next_sn => {
+                return Err(ControllerError::Parse(format!(
+                    "Key event log of {} has event {} where {} is expected",
+                    prefix.to_str(),
+                    sn,
+                    next_sn
+                )))
+            }
+            _ => next_sn += 1,
+        }
+    }
+    if next_sn == 0 {
+        return Err(ControllerError::Parse(format!(
+            "Key event log of {} has no events, only receipts",
+            prefix.to_str()
+        )));
+    }
+    if let Some(sn) = receipted_sns.into_iter().find(|sn| *sn >= next_sn) {
+        return Err(ControllerError::Parse(format!(
+            "Key event log of {} has receipt of missing event {}",
+            prefix.to_str(),
+            sn
+        )));
+    }
+    Ok((prefix, next_sn - 1))
+}
+
+/// Reads JSON bodies of events in key event log stream.
+fn kel_events(kel: &[u8]) -> Result<Vec<serde_json::Value>, ControllerError> {
+    let mut rest = std::str::from_utf8(kel)
+        .map_err(|e| ControllerError::Parse(format!("Key event log isn't valid CESR: {}", e)))?;
+    let mut events = vec![];
+    // Events are JSON objects, attachments between them never contain `{`.
+    while let Some(start) = rest.find('{') {
+        let mut stream =
+            serde_json::Deserializer::from_str(&rest[start..]).into_iter::<serde_json::Value>();
+        let event = stream
+            .next()
+            .transpose()
+            .map_err(|e| ControllerError::Parse(format!("Malformed key event log: {}", e)))?
+            .unwrap_or_default();
+        events.push(event);
+        rest = &rest[start + stream.byte_offset()..];
+    }
+    Ok(events)
+}
+
+fn event_field<'a>(event: &'a serde_json::Value, field: &str) -> &'a str {
+    event
+        .get(field)
+        .and_then(serde_json::Value::as_str)
+        .unwrap_or_default()
+}
+
+/// Returns `url` ending with a slash, so paths joined to it are appended to
+/// its path instead of replacing the last segment.
+pub fn base_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+/// Returns seals of `digests`, for anchoring them in an interaction event.
+fn digest_seals(digests: &[SelfAddressingPrefix]) -> Vec<Seal> {
+    digests
+        .iter()
+        .map(|dig| Seal::Digest(DigestSeal { dig: dig.clone() }))
+        .collect()
+}
+
+/// Splits CESR stream of a single signed event into the event, its
+/// serialization and the attached signatures.
+fn parse_signed_event(
+    stream: &[u8],
+) -> Result<(serde_json::Value, &[u8], Vec<AttachedSignaturePrefix>), ControllerError> {
+    let mut events = serde_json::Deserializer::from_slice(stream).into_iter::<serde_json::Value>();
+    let event = events
+        .next()
+        .transpose()
+        .map_err(|e| ControllerError::Parse(format!("Malformed event: {}", e)))?
+        .ok_or_else(|| ControllerError::Parse("Event is missing".into()))?;
+    let (serialized, signatures_stream) = stream.split_at(events.byte_offset());
+    match attachment(signatures_stream) {
+        Ok((_, Attachment::AttachedSignatures(signatures))) => Ok((event, serialized, signatures)),
+        _ => Err(ControllerError::Parse(
+            "Event has no attached signatures".into(),
+        )),
+    }
+}
+
+/// Checks that `signatures` of `serialized` event are made by the keys it
+/// establishes and meet its signing threshold. Weighted thresholds are only
+/// met by signatures of every key.
+fn check_event_signatures(
+    event: &serde_json::Value,
+    serialized: &[u8],
+    signatures: &[AttachedSignaturePrefix],
+) -> Result<(), ControllerError> {
+    let keys = event_list(event, "k")
+        .iter()
+        .map(|key| parse_prefix::<BasicPrefix>(key))
+        .collect::<Result<Vec<_>, _>>()?;
+    for signature in signatures {
+        let valid = keys
+            .get(signature.index as usize)
+            .map(|key| {
+                key.verify(serialized, &signature.signature)
+                    .unwrap_or(false)
+            })
+            .unwrap_or(false);
+        if !valid {
+            return Err(ControllerError::UnknownSignature(signature.to_str()));
+        }
+    }
+    let required = match event.get("kt").and_then(serde_json::Value::as_str) {
+        Some(threshold) => usize::from_str_radix(threshold, 16).unwrap_or(keys.len()),
+        None => keys.len(),
+    };
+    let mut signed = signatures.iter().map(|s| s.index).collect::<Vec<_>>();
+    signed.sort_unstable();
+    signed.dedup();
+    if signed.len() < required.max(1) {
+        return Err(ControllerError::ThresholdNotMet);
+    }
+    Ok(())
+}
+
+/// Finds the event of delegator's `kel` anchoring event `sn` of `prefix`
+/// with digest `digest`. Returns its sequence number and digest.
+fn find_anchor(
+    kel: &[u8],
+    prefix: &str,
+    sn: u64,
+    digest: &str,
+) -> Result<Option<(u64, String)>, ControllerError> {
+    for event in kel_events(kel)? {
+        let anchors = match event.get("a").and_then(serde_json::Value::as_array) {
+            Some(anchors) => anchors,
+            None => continue,
+        };
+        let anchored = anchors.iter().any(|seal| {
+            event_field(seal, "i") == prefix
+                && event_field(seal, "d") == digest
+                && u64::from_str_radix(event_field(seal, "s"), 16) == Ok(sn)
+        });
+        if anchored {
+            let anchor_sn = u64::from_str_radix(event_field(&event, "s"), 16).map_err(|_| {
+                ControllerError::Parse(format!("Invalid sequence number in event {}", event))
+            })?;
+            return Ok(Some((anchor_sn, event_field(&event, "d").to_string())));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns strings of event field holding a list, or a single value like the
+/// next key digest of older KERI versions.
+fn event_list(event: &serde_json::Value, field: &str) -> Vec<String> {
+    match event.get(field) {
+        Some(serde_json::Value::Array(values)) => values
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        Some(serde_json::Value::String(value)) if !value.is_empty() => vec![value.clone()],
+        _ => vec![],
+    }
+}
+
+/// Parses prefix taken from an url or another external source.
+fn parse_prefix<P: std::str::FromStr>(prefix: &str) -> Result<P, ControllerError>
+where
+    P::Err: std::fmt::Display,
+{
+    prefix
+        .parse()
+        .map_err(|e| ControllerError::Parse(format!("{}: {}", prefix, e)))
+}
+
+/// Returns signature derivation produced by keys of given type.
+fn signature_derivation(key_type: &Basic) -> SelfSigning {
+    match key_type {
+        Basic::ECDSAsecp256k1 | Basic::ECDSAsecp256k1NT => SelfSigning::ECDSAsecp256k1Sha256,
+        Basic::Ed448 | Basic::Ed448NT => SelfSigning::Ed448,
+        _ => SelfSigning::Ed25519Sha512,
+    }
+}
+
+/// Returns CESR attachment of indexed controller signatures: the `-A`
+/// counter with the signature count in two base64 digits, followed by the
+/// signatures.
+fn signatures_attachment(signatures: &[AttachedSignaturePrefix]) -> String {
+    const BASE64_DIGITS: &[u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let count = signatures.len();
+    let mut attachment = format!(
+        "-A{}{}",
+        BASE64_DIGITS[count / 64 % 64] as char,
+        BASE64_DIGITS[count % 64] as char
+    );
+    for signature in signatures {
+        attachment.push_str(&signature.to_str());
+    }
+    attachment
+}
+
+/// Returns CESR attachment of the seal of the delegator's event anchoring a
+/// delegated event: the `-G` counter for a single couple of the anchoring
+/// event's sequence number, as a 16 byte number, and digest.
+fn source_seal_attachment(sn: u64, digest: &str) -> String {
+    format!(
+        "-GAB0A{}{}",
+        base64::encode_config((sn as u128).to_be_bytes(), base64::URL_SAFE_NO_PAD),
+        digest
+    )
+}
+
+/// Checks response of witness at `ip` to a published event and processes the
+/// receipt it contains into the KEL. Returns the receipt.
+fn process_witness_response(
+    keri: &Keri<KeyStore>,
+    ip: &Url,
+    response: Result<PublishResponse>,
+) -> Result<String> {
+    let response = response?;
+    if !response.errors.is_empty() {
+        log::warn!(
+            "Witness {} reported errors: {}",
+            ip,
+            response.errors.join("; ")
+        );
+    }
+    let receipt = response.receipts.join("");
+    if receipt.is_empty() {
+        return Err(if response.errors.is_empty() {
+            anyhow::anyhow!("Witness {} returned no receipt", ip)
+        } else {
+            anyhow::anyhow!(
+                "Witness {} rejected event: {}",
+                ip,
+                response.errors.join("; ")
+            )
+        });
+    }
+    keri.parse_and_process(receipt.as_bytes())
+        .map_err(|e| ControllerError::Parse(e.to_string()))
+        .with_context(|| format!("Processing receipt of witness {} failed", ip))?;
+    Ok(receipt)
+}
+
+fn report_failed_witnesses(event_type: &str, failed_witnesses: &[BasicPrefix]) {
+    if !failed_witnesses.is_empty() {
+        log::warn!(
+            "Witnesses that didn't receive {} event: {:?}",
+            event_type,
+            failed_witnesses
+                .iter()
+                .map(|w| w.to_str())
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use keri::keys::PublicKey;
+    use warp::Filter;
+
+    use super::*;
+
+    /// Returns URL of a mock server bound to `addr`.
+    fn local_url(addr: SocketAddr) -> Url {
+        base_url(Url::parse(&format!("http://{}", addr)).unwrap())
+    }
+
+    /// Controller whose identifier is incepted without witnesses.
+    async fn incepted_controller(db_path: &Path, key_store: KeyStore) -> Result<Controller> {
+        Controller::init(
+            db_path,
+            vec![],
+            InceptionConfig::default(),
+            None,
+            key_store,
+            NetworkConfig::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn publishing_to_slow_witness_times_out() -> Result<()> {
+        let witness = warp::post().and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, Infallible>("")
+        });
+        let (addr, server) = warp::serve(witness).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let dir = tempfile::tempdir()?;
+        let controller = Controller::new(
+            dir.path(),
+            vec![],
+            KeyStore::ephemeral(),
+            NetworkConfig {
+                request_timeout: Duration::from_millis(100),
+                publish_retries: 0,
+                ..NetworkConfig::default()
+            },
+        )?;
+
+        let location = local_url(addr);
+        let started = Instant::now();
+        let error = controller
+            .transport
+            .publish(&location, b"{}")
+            .await
+            .err()
+            .expect("publishing to a witness that doesn't answer succeeded");
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(format!("{:#}", error).contains(location.as_str()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolved_witness_location_is_reused() -> Result<()> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let resolver = {
+            let requests = requests.clone();
+            warp::path!("witness_ips" / String).map(move |_witness| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                warp::reply::json(&serde_json::json!({ "ip": "127.0.0.1:3232" }))
+            })
+        };
+        let (addr, server) = warp::serve(resolver).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let dir = tempfile::tempdir()?;
+        let controller = Controller::new(
+            dir.path(),
+            vec![local_url(addr)],
+            KeyStore::ephemeral(),
+            NetworkConfig::default(),
+        )?;
+        let witness: BasicPrefix = parse_prefix("DA_REI14n-2_ev3ZdXtwcxbnbfLN-13G4LwcTdSZ-_Kc")?;
+
+        let first = controller.get_ips(&[witness.clone()]).await?;
+        let second = controller.get_ips(&[witness]).await?;
+
+        assert_eq!(first, second);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn secp256k1_signature_verifies() -> Result<()> {
+        use k256::ecdsa::{signature::Signer, Signature, SigningKey};
+
+        let signing_key = SigningKey::from_bytes(&[7; 32])?;
+        let key = Basic::ECDSAsecp256k1.derive(PublicKey::new(
+            signing_key
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec(),
+        ));
+        let key_config = KeyConfig::new(vec![key], None, Some(SignatureThreshold::Simple(1)));
+        let message = b"attestation";
+        let signature: Signature = signing_key.sign(message);
+        let signature = AttachedSignaturePrefix::new(
+            signature_derivation(&Basic::ECDSAsecp256k1),
+            signature.as_ref().to_vec(),
+            0,
+        );
+
+        verify_signatures(&key_config, message, &[signature.clone()])?;
+        assert!(verify_signatures(&key_config, b"other message", &[signature]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn saved_witness_location_survives_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let witness: WitnessConfig = serde_json::from_value(serde_json::json!({
+            "aid": "DA_REI14n-2_ev3ZdXtwcxbnbfLN-13G4LwcTdSZ-_Kc",
+            "location": "http://127.0.0.1:3232",
+        }))?;
+        let controller = Controller::new(
+            dir.path(),
+            vec![],
+            KeyStore::ephemeral(),
+            NetworkConfig::default(),
+        )?;
+        let saved = controller.save_witness_data(&[witness]).await?;
+        controller.close()?;
+
+        // Without resolvers, the location can only come from the database.
+        let controller = Controller::new(
+            dir.path(),
+            vec![],
+            KeyStore::ephemeral(),
+            NetworkConfig::default(),
+        )?;
+        let ips = controller.get_ips(&saved).await?;
+
+        assert_eq!(
+            ips,
+            vec![(saved[0].clone(), Url::parse("http://127.0.0.1:3232/")?)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotation_waits_for_event_in_progress() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = incepted_controller(dir.path(), KeyStore::ephemeral()).await?;
+
+        // Held by an event being published.
+        let event_guard = controller.event_lock.lock().await;
+        let rotation = controller.rotate(None, None, None);
+        tokio::pin!(rotation);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut rotation)
+                .await
+                .is_err()
+        );
+        assert_eq!(controller.get_state()?.map(|state| state.sn), Some(0));
+
+        drop(event_guard);
+        let (_event, state) = rotation.await?;
+        assert_eq!(state.sn, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_rotations_are_applied_in_turn() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = incepted_controller(dir.path(), KeyStore::ephemeral()).await?;
+
+        let (first, second) = tokio::join!(
+            controller.rotate(None, None, None),
+            controller.rotate(None, None, None)
+        );
+        let mut sns = vec![first?.1.sn, second?.1.sn];
+        sns.sort_unstable();
+
+        assert_eq!(sns, vec![1, 2]);
+        assert_eq!(controller.get_state()?.map(|state| state.sn), Some(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotation_without_receipts_succeeds() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = incepted_controller(dir.path(), KeyStore::ephemeral()).await?;
+        let incepted = controller
+            .get_state()?
+            .ok_or(ControllerError::NotIncepted)?;
+        assert!(controller.get_receipts()?.is_empty());
+
+        let (_event, rotated) = controller.rotate(None, None, None).await?;
+
+        assert_eq!(rotated.sn, 1);
+        assert_ne!(rotated.current.public_keys, incepted.current.public_keys);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn threshold_only_rotation_keeps_witnesses() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = incepted_controller(dir.path(), KeyStore::ephemeral()).await?;
+
+        // No witness list, so there are no new witnesses to send the KEL to.
+        let (_event, rotated) = controller
+            .rotate(None, Some(SignatureThreshold::Simple(0)), None)
+            .await?;
+
+        assert_eq!(rotated.sn, 1);
+        assert!(rotated.witness_config.witnesses.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn init_incepts_on_first_run_and_loads_on_restart() -> Result<()> {
+        const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+        let dir = tempfile::tempdir()?;
+
+        let controller = incepted_controller(dir.path(), KeyStore::from_seed(SEED, 1, 1)?).await?;
+        let prefix = controller.get_prefix();
+        controller.rotate(None, None, None).await?;
+        controller.close()?;
+
+        // Seeded keys start over at the inception keys and have to follow
+        // the rotation in the stored KEL.
+        let controller = incepted_controller(dir.path(), KeyStore::from_seed(SEED, 1, 1)?).await?;
+        let state = controller
+            .get_state()?
+            .ok_or(ControllerError::NotIncepted)?;
+
+        assert_eq!(controller.get_prefix(), prefix);
+        assert_eq!(state.sn, 1);
+        assert_eq!(controller.get_kel_events()?.len(), 2);
+        assert!(!controller.controlled_keys()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn two_key_identifier_is_incepted_and_rotated() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = Controller::init(
+            dir.path(),
+            vec![],
+            InceptionConfig {
+                signing_threshold: Some(SignatureThreshold::Simple(2)),
+                ..InceptionConfig::default()
+            },
+            None,
+            KeyStore::generate(2, 2),
+            NetworkConfig::default(),
+        )
+        .await?;
+        let incepted = controller
+            .get_state()?
+            .ok_or(ControllerError::NotIncepted)?;
+
+        let (_event, rotated) = controller.rotate(None, None, None).await?;
+
+        assert_eq!(incepted.current.public_keys.len(), 2);
+        assert_eq!(rotated.sn, 1);
+        assert_eq!(rotated.current.public_keys.len(), 2);
+        assert_ne!(rotated.current.public_keys, incepted.current.public_keys);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signatures_of_two_key_identifier_meet_threshold() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let controller = Controller::init(
+            dir.path(),
+            vec![],
+            InceptionConfig {
+                signing_threshold: Some(SignatureThreshold::Simple(2)),
+                ..InceptionConfig::default()
+            },
+            None,
+            KeyStore::generate(2, 2),
+            NetworkConfig::default(),
+        )
+        .await?;
+        let data = b"attestation";
+
+        let signatures = controller.sign_all(data)?;
+
+        assert_eq!(
+            signatures.iter().map(|s| s.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        controller
+            .verify(&controller.get_prefix(), data, &signatures)
+            .await?;
+        assert!(controller
+            .verify(&controller.get_prefix(), data, &signatures[..1])
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restart_with_generated_keys_asks_to_keep_them() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        incepted_controller(dir.path(), KeyStore::ephemeral())
+            .await?
+            .close()?;
+
+        let error = match incepted_controller(dir.path(), KeyStore::ephemeral()).await {
+            Ok(_) => panic!("identifier was loaded with keys that don't control it"),
+            Err(error) => format!("{:#}", error),
+        };
+        assert!(error.contains("key_store_path"));
+        assert!(error.contains(KEY_SEED_ENV));
+        Ok(())
     }
 }
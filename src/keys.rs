@@ -0,0 +1,445 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{Keypair, PublicKey as DalekPublicKey, SecretKey, Signer};
+use keri::{
+    derivation::basic::Basic, error::Error, keys::PublicKey, prefix::BasicPrefix,
+    signer::KeyManager,
+};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+/// Environment variable holding the passphrase of the key store file.
+pub const KEY_PASSPHRASE_ENV: &str = "ACDCD_KEY_PASSPHRASE";
+
+/// Environment variable holding the hex encoded seed keys are derived from.
+pub const KEY_SEED_ENV: &str = "ACDCD_KEY_SEED";
+
+/// Controller's current key pairs and the next ones, committed to in the
+/// last establishment event, ordered like the keys in the event. Keys can be
+/// kept in a passphrase encrypted file, so the identifier survives
+/// redeployment, otherwise they only live in memory.
+pub struct KeyStore {
+    current: Vec<Keypair>,
+    next: Vec<Keypair>,
+    /// Key pairs which replace `next` on rotation, so the next rotation
+    /// commits to them. They're generated ahead and saved with the other
+    /// keys, so a previewed rotation commits to the same keys as the real one.
+    following: Vec<Keypair>,
+    file: Option<(PathBuf, String)>,
+    /// Seed all key pairs are derived from. Keys are generated randomly
+    /// without it.
+    seed: Option<Seed>,
+}
+
+struct Seed {
+    seed: Vec<u8>,
+    /// Number of establishment events before the one establishing the
+    /// current keys.
+    index: u32,
+    key_count: usize,
+    next_key_count: usize,
+}
+
+impl Seed {
+    /// Derives key pairs established by establishment event number `index`:
+    /// `key_count` inception keys, `next_key_count` keys for every rotation.
+    fn keypairs(&self, index: u32) -> Result<Vec<Keypair>> {
+        let count = if index == 0 {
+            self.key_count
+        } else {
+            self.next_key_count
+        };
+        (0..count)
+            .map(|position| seeded_keypair(&self.seed, index, position))
+            .collect()
+    }
+}
+
+/// Contents of the key store file.
+#[derive(Serialize, Deserialize)]
+struct EncryptedKeys {
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKeys {
+    current: StoredSlot,
+    next: StoredSlot,
+    /// Missing in key stores saved before the keys were generated ahead.
+    #[serde(default)]
+    following: Option<StoredSlot>,
+}
+
+/// Secret keys of one establishment event. Key stores saved before
+/// multiple keys were supported hold a single key.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredSlot {
+    Single(Vec<u8>),
+    Multiple(Vec<Vec<u8>>),
+}
+
+impl StoredSlot {
+    fn new(keys: &[Keypair]) -> Self {
+        StoredSlot::Multiple(
+            keys.iter()
+                .map(|key| key.secret.to_bytes().to_vec())
+                .collect(),
+        )
+    }
+
+    fn keypairs(&self) -> Result<Vec<Keypair>> {
+        match self {
+            StoredSlot::Single(secret) => Ok(vec![keypair(secret)?]),
+            StoredSlot::Multiple(secrets) => secrets.iter().map(|secret| keypair(secret)).collect(),
+        }
+    }
+}
+
+impl KeyStore {
+    /// Generates a single current and next key that won't be saved
+    /// anywhere.
+    pub fn ephemeral() -> Self {
+        KeyStore::generate(1, 1)
+    }
+
+    /// Generates `key_count` current and `next_key_count` next keys that
+    /// won't be saved anywhere.
+    pub fn generate(key_count: usize, next_key_count: usize) -> Self {
+        KeyStore {
+            current: generate_keypairs(key_count),
+            next: generate_keypairs(next_key_count),
+            following: generate_keypairs(next_key_count),
+            file: None,
+            seed: None,
+        }
+    }
+
+    /// Derives keys from `seed`, so the same seed and key counts always give
+    /// the same inception keys and the same sequence of rotated keys. Keys
+    /// aren't saved anywhere, the seed is enough to get them back.
+    pub fn from_seed(seed: &str, key_count: usize, next_key_count: usize) -> Result<Self> {
+        let seed = decode_hex(seed.trim()).context("Key seed has to be hex encoded")?;
+        if seed.len() < 16 {
+            return Err(anyhow::anyhow!("Key seed has to be at least 16 bytes long"));
+        }
+        let seed = Seed {
+            seed,
+            index: 0,
+            key_count,
+            next_key_count,
+        };
+        Ok(KeyStore {
+            current: seed.keypairs(0)?,
+            next: seed.keypairs(1)?,
+            following: seed.keypairs(2)?,
+            file: None,
+            seed: Some(seed),
+        })
+    }
+
+    /// Whether keys are generated on every start, so they can't control an
+    /// identifier incepted before.
+    pub fn is_ephemeral(&self) -> bool {
+        self.file.is_none() && self.seed.is_none()
+    }
+
+    /// Derives seeded keys as they are after `rotations` rotations since
+    /// inception, so keys of an identifier rotated before a restart are
+    /// restored. Keys that aren't derived from a seed are kept.
+    pub fn skip_rotations(&mut self, rotations: u32) -> Result<()> {
+        let seed = match &mut self.seed {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+        self.current = seed.keypairs(rotations)?;
+        self.next = seed.keypairs(rotations + 1)?;
+        self.following = seed.keypairs(rotations + 2)?;
+        seed.index = rotations;
+        Ok(())
+    }
+
+    /// Loads keys from `path`, or generates `key_count` current and
+    /// `next_key_count` next keys and saves them there if the file doesn't
+    /// exist yet. Keys already saved are loaded whatever the counts.
+    pub fn open(
+        path: &Path,
+        passphrase: String,
+        key_count: usize,
+        next_key_count: usize,
+    ) -> Result<Self> {
+        if !path.exists() {
+            let keys = KeyStore {
+                file: Some((path.to_path_buf(), passphrase)),
+                ..KeyStore::generate(key_count, next_key_count)
+            };
+            keys.save()?;
+            return Ok(keys);
+        }
+
+        let encrypted: EncryptedKeys = serde_json::from_slice(
+            &fs::read(path).with_context(|| format!("Reading key store {:?} failed", path))?,
+        )
+        .with_context(|| format!("Key store {:?} is malformed", path))?;
+        let stored: StoredKeys = serde_json::from_slice(
+            &cipher(&passphrase, &encrypted.salt)?
+                .decrypt(
+                    Nonce::from_slice(&encrypted.nonce),
+                    encrypted.ciphertext.as_slice(),
+                )
+                .map_err(|_| anyhow::anyhow!("Can't decrypt key store {:?}", path))?,
+        )?;
+
+        let next = stored.next.keypairs()?;
+        let keys = KeyStore {
+            current: stored.current.keypairs()?,
+            following: match &stored.following {
+                Some(following) => following.keypairs()?,
+                None => generate_keypairs(next.len()),
+            },
+            next,
+            file: Some((path.to_path_buf(), passphrase)),
+            seed: None,
+        };
+        if stored.following.is_none() {
+            keys.save()?;
+        }
+        Ok(keys)
+    }
+
+    fn save(&self) -> Result<()> {
+        let (path, passphrase) = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let stored = serde_json::to_vec(&StoredKeys {
+            current: StoredSlot::new(&self.current),
+            next: StoredSlot::new(&self.next),
+            following: Some(StoredSlot::new(&self.following)),
+        })?;
+        let salt = rand::random::<[u8; 16]>();
+        let nonce = rand::random::<[u8; 12]>();
+        let ciphertext = cipher(passphrase, &salt)?
+            .encrypt(Nonce::from_slice(&nonce), stored.as_slice())
+            .map_err(|_| anyhow::anyhow!("Encrypting keys failed"))?;
+
+        // Write the whole file first, so the keys are never lost halfway.
+        let tmp_path = path.with_extension("tmp");
+        fs::write(
+            &tmp_path,
+            serde_json::to_vec(&EncryptedKeys {
+                salt: salt.to_vec(),
+                nonce: nonce.to_vec(),
+                ciphertext,
+            })?,
+        )?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Saving key store {:?} failed", path))?;
+        Ok(())
+    }
+
+    /// Type of every key in the store.
+    pub fn key_type(&self) -> Basic {
+        Basic::Ed25519
+    }
+
+    /// Keys established as current by the last establishment event.
+    pub fn current_keys(&self) -> Vec<BasicPrefix> {
+        self.prefixes(&self.current)
+    }
+
+    /// Keys committed to as next by the last establishment event, which the
+    /// upcoming rotation establishes.
+    pub fn next_keys(&self) -> Vec<BasicPrefix> {
+        self.prefixes(&self.next)
+    }
+
+    /// Keys the upcoming rotation commits to as next.
+    pub fn following_keys(&self) -> Vec<BasicPrefix> {
+        self.prefixes(&self.following)
+    }
+
+    /// Signs with every current key, in the order of `current_keys`.
+    pub fn sign_all(&self, msg: &[u8]) -> Vec<Vec<u8>> {
+        sign_with(&self.current, msg)
+    }
+
+    /// Signs with every next key, in the order of `next_keys`, as a rotation
+    /// establishing them has to be.
+    pub fn sign_all_with_next(&self, msg: &[u8]) -> Vec<Vec<u8>> {
+        sign_with(&self.next, msg)
+    }
+
+    fn prefixes(&self, keys: &[Keypair]) -> Vec<BasicPrefix> {
+        keys.iter()
+            .map(|key| {
+                self.key_type()
+                    .derive(PublicKey::new(key.public.to_bytes().to_vec()))
+            })
+            .collect()
+    }
+
+    /// Generates key pairs that follow `next`, derived from the seed if
+    /// there is one.
+    fn generate_following(&self) -> Result<Vec<Keypair>> {
+        match &self.seed {
+            Some(seed) => seed.keypairs(seed.index + 2),
+            None => Ok(generate_keypairs(self.next.len())),
+        }
+    }
+}
+
+/// Signs with the first key only, `sign_all` signs with every key.
+impl KeyManager for KeyStore {
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.current[0].sign(msg).to_bytes().to_vec())
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey::new(self.current[0].public.to_bytes().to_vec())
+    }
+
+    fn next_public_key(&self) -> PublicKey {
+        PublicKey::new(self.next[0].public.to_bytes().to_vec())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        if let Some(seed) = &mut self.seed {
+            seed.index += 1;
+        }
+        let following = self
+            .generate_following()
+            .map_err(|e| Error::SemanticError(format!("{:#}", e)))?;
+        self.current = std::mem::replace(
+            &mut self.next,
+            std::mem::replace(&mut self.following, following),
+        );
+        self.save()
+            .map_err(|e| Error::SemanticError(format!("{:#}", e)))
+    }
+}
+
+/// Derives key store encryption key from the passphrase.
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Deriving key store key failed: {}", e))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Derives the key pair at `position` among the keys established by
+/// establishment event number `index` from the seed, the first inception key
+/// being the first one. The first key of every event is derived the same as
+/// when only single keys were supported.
+fn seeded_keypair(seed: &[u8], index: u32, position: usize) -> Result<Keypair> {
+    let salt = match position {
+        0 => format!("acdcd key {}", index),
+        _ => format!("acdcd key {}.{}", index, position),
+    };
+    let mut secret = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(seed, salt.as_bytes(), &mut secret)
+        .map_err(|e| anyhow::anyhow!("Deriving key from seed failed: {}", e))?;
+    keypair(&secret)
+}
+
+fn generate_keypairs(count: usize) -> Vec<Keypair> {
+    (0..count).map(|_| Keypair::generate(&mut OsRng)).collect()
+}
+
+fn sign_with(keys: &[Keypair], msg: &[u8]) -> Vec<Vec<u8>> {
+    keys.iter()
+        .map(|key| key.sign(msg).to_bytes().to_vec())
+        .collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(anyhow::anyhow!("Odd length or non-ASCII hex string"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+fn keypair(secret: &[u8]) -> Result<Keypair> {
+    let secret = SecretKey::from_bytes(secret).context("Stored key is malformed")?;
+    let public = DalekPublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_store_reopened_has_same_keys() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys.json");
+
+        let first = KeyStore::open(&path, "passphrase".into(), 1, 1)?;
+        let second = KeyStore::open(&path, "passphrase".into(), 1, 1)?;
+
+        assert_eq!(first.public_key().key(), second.public_key().key());
+        assert_eq!(
+            first.next_public_key().key(),
+            second.next_public_key().key()
+        );
+        assert_eq!(first.following_keys(), second.following_keys());
+        Ok(())
+    }
+
+    #[test]
+    fn key_store_reopened_after_rotation_has_rotated_keys() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys.json");
+
+        let mut keys = KeyStore::open(&path, "passphrase".into(), 1, 1)?;
+        let next = keys.next_public_key();
+        keys.rotate()?;
+        let reopened = KeyStore::open(&path, "passphrase".into(), 1, 1)?;
+
+        assert_eq!(reopened.public_key().key(), next.key());
+        assert_eq!(reopened.next_keys(), keys.next_keys());
+        Ok(())
+    }
+
+    #[test]
+    fn key_store_reopened_keeps_saved_key_counts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys.json");
+
+        let first = KeyStore::open(&path, "passphrase".into(), 3, 2)?;
+        let second = KeyStore::open(&path, "passphrase".into(), 1, 1)?;
+
+        assert_eq!(second.current_keys().len(), 3);
+        assert_eq!(second.current_keys(), first.current_keys());
+        assert_eq!(second.next_keys(), first.next_keys());
+        assert_eq!(second.following_keys().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn key_store_with_wrong_passphrase_fails() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys.json");
+
+        KeyStore::open(&path, "passphrase".into(), 1, 1)?;
+
+        assert!(KeyStore::open(&path, "other passphrase".into(), 1, 1).is_err());
+        Ok(())
+    }
+}
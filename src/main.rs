@@ -1,28 +1,134 @@
 mod api;
 mod controller;
+mod keys;
+mod metrics;
+mod schema;
+mod storage;
+mod tel;
+mod transport;
 
-use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
-use controller::Controller;
+use controller::{
+    base_url, validate_key_threshold, validate_threshold, Controller, InceptionConfig,
+    NetworkConfig, ThresholdConfig,
+};
 use figment::{
-    providers::{Format, Json},
+    providers::{Env, Format, Json, Toml, Yaml},
     Figment,
 };
-use keri::{event::sections::threshold::SignatureThreshold, prefix::BasicPrefix};
+use futures::FutureExt;
+use keri::prefix::{BasicPrefix, IdentifierPrefix, Prefix};
+use reqwest::Client;
 use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
 use structopt::StructOpt;
-use tokio::sync::RwLock;
+use tokio::{
+    net::{TcpListener, UnixListener},
+    signal::unix::{signal, SignalKind},
+    sync::{OnceCell, RwLock},
+};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tracing_subscriber::EnvFilter;
 use url::Url;
 
-use self::api::{setup_routes, AttestationDB};
+use self::{
+    api::{setup_routes, ServedIdentifier},
+    keys::{KeyStore, KEY_PASSPHRASE_ENV, KEY_SEED_ENV},
+    storage::AttestationStore,
+};
+
+/// Request body size limit used when `max_body_size` isn't configured.
+const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
 
 #[derive(Deserialize)]
 struct Config {
     kel_db_path: PathBuf,
+    /// File keeping controller keys, encrypted with passphrase taken from
+    /// `ACDCD_KEY_PASSPHRASE` environment variable. Keys are generated on
+    /// every start if it's not set.
+    key_store_path: Option<PathBuf>,
+    /// Attestation database path. Defaults to a directory inside `kel_db_path`.
+    attestation_db_path: Option<PathBuf>,
+    /// Most attestations kept in memory, the oldest ones are dropped from
+    /// memory first and read from the database when needed. Unlimited if
+    /// not set.
+    max_attestations: Option<usize>,
+    /// Daemon API listen address, IPv4 or IPv6.
     api_host: String,
     /// Daemon API listen port.
     api_port: u16,
+    /// Accept IPv4 connections too when listening on `::`, whatever the
+    /// system default for IPv6 sockets is.
+    dual_stack: Option<bool>,
+    /// Serve identifiers incepted by another instance without their keys.
+    /// Routes which sign or change the KEL are disabled.
+    read_only: Option<bool>,
+    /// Serve the API on this unix domain socket instead of `api_host` and
+    /// `api_port`.
+    unix_socket_path: Option<PathBuf>,
+    /// Certificate chain (PEM) used to serve the API over TLS.
+    tls_cert_path: Option<PathBuf>,
+    /// Private key (PEM) of the TLS certificate.
+    tls_key_path: Option<PathBuf>,
+    /// Timeout of witness and resolver requests, in seconds.
+    request_timeout_secs: Option<u64>,
+    /// How long witness addresses obtained from resolvers are reused, in
+    /// seconds.
+    witness_ip_ttl_secs: Option<u64>,
+    /// How long key states and issuer keys obtained from resolvers are
+    /// reused, in seconds.
+    key_state_ttl_secs: Option<u64>,
+    /// How many times sending to a witness is retried before giving up.
+    publish_retries: Option<u32>,
+    /// Delay before the first retry of sending to a witness, in milliseconds.
+    publish_backoff_ms: Option<u64>,
+    /// How long publishing to a single witness can take, retries included,
+    /// in milliseconds.
+    per_witness_timeout_ms: Option<u64>,
+    /// Most requests sent to witnesses at once when publishing an event.
+    /// Unlimited if not set.
+    max_concurrent_witness_requests: Option<usize>,
+    /// Retry failed verification once with the issuer's key event log fetched
+    /// from every resolver. Enabled by default.
+    refresh_stale_keys: Option<bool>,
+    /// Delay between polls of witness mailboxes, and of the delegator's key
+    /// event log, in milliseconds.
+    mailbox_poll_interval_ms: Option<u64>,
+    /// How long the delegator has to anchor delegated inception or rotation,
+    /// in seconds.
+    delegation_timeout_secs: Option<u64>,
+    /// Origins of browser clients allowed to call the API. Cross-origin
+    /// requests are rejected if none are set.
+    allowed_origins: Option<Vec<Url>>,
+    /// Token required in `Authorization: Bearer` header of requests that sign
+    /// or change anything. Such requests aren't restricted if it's not set.
+    api_token: Option<String>,
+    /// Issuers whose attestations are accepted when received or imported.
+    /// Any issuer is accepted if it's empty or not set.
+    issuer_allowlist: Option<Vec<IdentifierPrefix>>,
+    /// Largest accepted request body, in bytes. Defaults to 1 MiB.
+    max_body_size: Option<u64>,
+    bootstrap: BootstrapConfig,
+    /// Further identifiers served by the daemon, under `/{prefix}/` paths.
+    identifiers: Option<Vec<IdentifierConfig>>,
+}
+
+/// Identifier served next to the main one configured at the top level.
+#[derive(Deserialize)]
+struct IdentifierConfig {
+    kel_db_path: PathBuf,
+    /// Encrypted with the same passphrase as the main key store.
+    key_store_path: Option<PathBuf>,
+    /// Defaults to a directory inside `kel_db_path`.
+    attestation_db_path: Option<PathBuf>,
     bootstrap: BootstrapConfig,
 }
 
@@ -30,30 +136,80 @@ struct Config {
 struct BootstrapConfig {
     witnesses: Option<Vec<WitnessConfig>>,
     known_resolvers: Option<Vec<Url>>,
-    witness_threshold: u64,
+    /// Number of witnesses, or list of fractional witness weights.
+    witness_threshold: ThresholdConfig,
+    /// Number of current signing keys incepted with, 1 if not set.
+    key_count: Option<usize>,
+    /// Number of next keys committed to, at inception and on every
+    /// rotation, 1 if not set.
+    next_key_count: Option<usize>,
+    /// Number of keys, or list of fractional key weights, that have to sign
+    /// events and attestations, for current and next keys alike. 1 if not
+    /// set.
+    signing_threshold: Option<ThresholdConfig>,
+    /// Identifier which anchors inception and rotations of a delegated
+    /// identifier. The identifier isn't delegated if not set.
+    delegator: Option<DelegatorConfig>,
+}
+
+/// Delegator served by another tda instance, which anchors delegated events
+/// posted to its `delegate` route.
+#[derive(Clone, Deserialize)]
+pub struct DelegatorConfig {
+    pub aid: IdentifierPrefix,
+    /// URL of the delegator's tda API.
+    pub location: Url,
+    /// Token of the delegator's API, if it requires one.
+    pub api_token: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct WitnessConfig {
     pub aid: Option<BasicPrefix>,
     pub location: Option<Url>,
+    /// Path under `location` of the mailbox where the witness leaves
+    /// receipts, for witnesses which don't return them when an event is
+    /// published. Receipts are read from the publish response if not set.
+    pub mailbox_path: Option<String>,
+    /// Identifier discovered by asking the witness at `location`.
+    #[serde(skip)]
+    discovered_aid: OnceCell<BasicPrefix>,
 }
 
 impl WitnessConfig {
-    pub fn get_aid(&self) -> Result<BasicPrefix> {
-        match &self.aid {
-            Some(aid) => Ok(aid.clone()),
-            None => {
-                //ask about prefix
-                todo!()
-            }
+    /// Returns witness identifier. If only the witness location is
+    /// configured, the witness is asked about its identifier.
+    pub async fn get_aid(&self, client: &Client) -> Result<BasicPrefix> {
+        match (&self.aid, &self.location) {
+            (Some(aid), _) => Ok(aid.clone()),
+            (None, Some(location)) => self
+                .discovered_aid
+                .get_or_try_init(|| async {
+                    let aid = client
+                        .get(base_url(location.clone()).join("introduce")?)
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .text()
+                        .await?;
+                    aid.trim()
+                        .trim_matches('"')
+                        .parse::<BasicPrefix>()
+                        .map_err(|e| anyhow::anyhow!(e.to_string()))
+                })
+                .await
+                .cloned()
+                .with_context(|| format!("Can't get identifier of witness at {}", location)),
+            (None, None) => Err(anyhow::anyhow!(
+                "Witness config needs at least one of aid or location"
+            )),
         }
     }
 
     pub fn get_location(&self) -> Result<Url> {
         self.location
-            .as_ref()
-            .cloned()
+            .clone()
+            .map(base_url)
             .ok_or(anyhow::anyhow!("No location set"))
     }
 }
@@ -62,49 +218,595 @@ impl WitnessConfig {
 struct Opts {
     #[structopt(short = "c", long, default_value = "config.json")]
     config_file: String,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Runs the API server. Used when no command is given.
+    Serve,
+    /// Incepts the controller, prints its identifier and exits.
+    Incept,
+    /// Rotates controller keys, prints the updated key event log and exits.
+    Rotate {
+        /// Identifier of a witness in the new witness set, can be repeated.
+        #[structopt(short, long = "witness")]
+        witnesses: Vec<BasicPrefix>,
+        /// New witness threshold, a number or comma separated fractional
+        /// weights, like `1/2,1/2,1/2`.
+        #[structopt(short, long)]
+        threshold: Option<ThresholdConfig>,
+    },
+    /// Prints the key event log and exits.
+    ShowKel,
+    /// Checks that configured witnesses and resolvers are reachable, without
+    /// incepting or changing anything. Fails if any of them isn't.
+    Doctor,
+}
+
+/// Picks config file format by its extension, JSON is used by default.
+/// Values from the file can be overridden by `ACDCD_` prefixed environment
+/// variables, with nested keys separated by `__`, for example
+/// `ACDCD_BOOTSTRAP__WITNESS_THRESHOLD`.
+fn config_figment(config_file: &str) -> Figment {
+    let figment = match Path::new(config_file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some("toml") => Figment::new().join(Toml::file(config_file)),
+        Some("yaml") | Some("yml") => Figment::new().join(Yaml::file(config_file)),
+        _ => Figment::new().join(Json::file(config_file)),
+    };
+    figment.merge(Env::prefixed("ACDCD_").split("__"))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init_from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
-    );
+    // `log` records of the daemon and its dependencies end up in the same
+    // output as tracing spans, filtered by `RUST_LOG` as before.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
-    let Opts { config_file } = Opts::from_args();
+    let Opts {
+        config_file,
+        command,
+    } = Opts::from_args();
 
     let Config {
         kel_db_path,
+        key_store_path,
+        attestation_db_path,
+        max_attestations,
         api_host,
         api_port,
+        dual_stack,
+        read_only,
+        unix_socket_path,
+        tls_cert_path,
+        tls_key_path,
+        request_timeout_secs,
+        witness_ip_ttl_secs,
+        key_state_ttl_secs,
+        publish_retries,
+        publish_backoff_ms,
+        per_witness_timeout_ms,
+        max_concurrent_witness_requests,
+        refresh_stale_keys,
+        mailbox_poll_interval_ms,
+        delegation_timeout_secs,
+        allowed_origins,
+        api_token,
+        issuer_allowlist,
+        max_body_size,
         bootstrap,
-    } = Figment::new().join(Json::file(config_file)).extract()?;
+        identifiers,
+    } = config_figment(&config_file).extract()?;
 
-    match bootstrap.witnesses {
-        Some(ref wit) if (wit.len() as u64) < bootstrap.witness_threshold => {
-            // not enough witnesses, any event can be accepted.
-            Err(anyhow::anyhow!("Not enough witnesses provided"))
-        }
-        _ => Ok(()),
-    }?;
+    let mut network_config = NetworkConfig::default();
+    if let Some(timeout) = request_timeout_secs {
+        network_config.request_timeout = Duration::from_secs(timeout);
+    }
+    if let Some(ttl) = witness_ip_ttl_secs {
+        network_config.witness_ip_ttl = Duration::from_secs(ttl);
+    }
+    if let Some(ttl) = key_state_ttl_secs {
+        network_config.key_state_ttl = Duration::from_secs(ttl);
+    }
+    if let Some(retries) = publish_retries {
+        network_config.publish_retries = retries;
+    }
+    if let Some(backoff) = publish_backoff_ms {
+        network_config.publish_backoff = Duration::from_millis(backoff);
+    }
+    if let Some(timeout) = per_witness_timeout_ms {
+        network_config.witness_timeout = Duration::from_millis(timeout);
+    }
+    network_config.max_concurrent_witness_requests = max_concurrent_witness_requests;
+    if let Some(refresh) = refresh_stale_keys {
+        network_config.refresh_stale_keys = refresh;
+    }
+    if let Some(interval) = mailbox_poll_interval_ms {
+        network_config.mailbox_poll_interval = Duration::from_millis(interval);
+    }
+    if let Some(timeout) = delegation_timeout_secs {
+        network_config.delegation_timeout = Duration::from_secs(timeout);
+    }
+
+    if let Some(Command::Doctor) = command {
+        return doctor(
+            bootstrap.witnesses.unwrap_or_default(),
+            bootstrap.known_resolvers.unwrap_or_default(),
+            network_config,
+        )
+        .await;
+    }
 
-    let cont = Controller::init(
+    let read_only = read_only.unwrap_or(false);
+    let command = command.unwrap_or(Command::Serve);
+    if read_only && matches!(command, Command::Incept | Command::Rotate { .. }) {
+        return Err(anyhow::anyhow!(
+            "Keys can't be incepted or rotated with read_only set"
+        ));
+    }
+
+    // The seed is only used for the top level identifier, other ones would
+    // get the same keys from it. Only serving and inception may incept, the
+    // key event log is shown without loading keys.
+    let cont = init_controller(
         &kel_db_path,
-        bootstrap.known_resolvers.unwrap_or_default(),
-        bootstrap.witnesses,
-        Some(SignatureThreshold::Simple(bootstrap.witness_threshold)),
+        key_store_path,
+        std::env::var(KEY_SEED_ENV).ok(),
+        bootstrap,
+        read_only || matches!(command, Command::ShowKel),
+        matches!(command, Command::Serve | Command::Incept),
+        network_config.clone(),
     )
     .await
     .context("Controller init failed")?;
 
-    let controller = Arc::new(RwLock::new(cont));
-    let attest_db: AttestationDB = Arc::new(RwLock::new(HashMap::new()));
+    match command {
+        Command::Serve => (),
+        Command::Incept => {
+            println!("{}", cont.get_prefix().to_str());
+            return cont.close();
+        }
+        Command::Rotate {
+            witnesses,
+            threshold,
+        } => {
+            let witnesses = (!witnesses.is_empty()).then(|| {
+                witnesses
+                    .into_iter()
+                    .map(|aid| WitnessConfig {
+                        aid: Some(aid),
+                        location: None,
+                        mailbox_path: None,
+                        discovered_aid: OnceCell::new(),
+                    })
+                    .collect()
+            });
+            let threshold = threshold
+                .map(|threshold| threshold.to_threshold())
+                .transpose()?;
+            cont.rotate(witnesses, threshold, None).await?;
+            println!("{}", cont.get_kel()?);
+            return cont.close();
+        }
+        Command::ShowKel => {
+            println!("{}", cont.get_kel()?);
+            return cont.close();
+        }
+        Command::Doctor => unreachable!("doctor runs before controller init"),
+    }
+
+    let mut served = vec![serve_identifier(
+        cont,
+        attestation_db_path.unwrap_or_else(|| kel_db_path.join("attestations")),
+        max_attestations,
+    )?];
+    for identifier in identifiers.unwrap_or_default() {
+        let cont = init_controller(
+            &identifier.kel_db_path,
+            identifier.key_store_path,
+            None,
+            identifier.bootstrap,
+            read_only,
+            true,
+            network_config.clone(),
+        )
+        .await
+        .with_context(|| format!("Init of identifier in {:?} failed", identifier.kel_db_path))?;
+        let identifier = serve_identifier(
+            cont,
+            identifier
+                .attestation_db_path
+                .unwrap_or_else(|| identifier.kel_db_path.join("attestations")),
+            max_attestations,
+        )?;
+        if served
+            .iter()
+            .any(|served| served.prefix == identifier.prefix)
+        {
+            return Err(anyhow::anyhow!(
+                "Identifier {} is configured twice",
+                identifier.prefix
+            ));
+        }
+        served.push(identifier);
+    }
+
+    let allowed_origins = allowed_origins
+        .unwrap_or_default()
+        .iter()
+        .map(|origin| origin.origin().ascii_serialization())
+        .collect::<Vec<_>>();
+    let cors = warp::cors()
+        .allow_origins(allowed_origins.iter().map(String::as_str))
+        .allow_methods(vec!["GET", "POST", "DELETE"])
+        .allow_headers(vec!["content-type", "authorization"]);
+    let routes = setup_routes(
+        &served,
+        api_token,
+        issuer_allowlist
+            .unwrap_or_default()
+            .iter()
+            .map(Prefix::to_str)
+            .collect(),
+        max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+        read_only,
+        cors,
+    );
 
-    let cors = warp::cors().allow_any_origin();
-    let routes = setup_routes(controller, attest_db, cors);
+    let bind_address = SocketAddr::from((
+        api_host
+            .parse::<IpAddr>()
+            .with_context(|| format!("api_host {:?} isn't an IP address", api_host))?,
+        api_port,
+    ));
+    let dual_stack = dual_stack.unwrap_or(false);
+    let server = warp::serve(routes);
+    let (tls, (addr, server)) = match (&unix_socket_path, tls_cert_path, tls_key_path) {
+        (Some(_), _, _) if dual_stack => {
+            return Err(anyhow::anyhow!(
+                "dual_stack can't be used together with unix_socket_path"
+            ))
+        }
+        (Some(_), Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "TLS can't be used together with unix_socket_path"
+            ))
+        }
+        (Some(path), None, None) => {
+            let listener = unix_listener(path)
+                .with_context(|| format!("Binding unix socket {:?} failed", path))?;
+            let server = server.serve_incoming_with_graceful_shutdown(
+                UnixListenerStream::new(listener),
+                shutdown_signal(),
+            );
+            (false, (format!("unix:{}", path.display()), server.boxed()))
+        }
+        (_, Some(_), Some(_)) if dual_stack => {
+            return Err(anyhow::anyhow!(
+                "dual_stack can't be used together with TLS"
+            ))
+        }
+        (_, Some(cert_path), Some(key_path)) => {
+            let (addr, server) = server
+                .tls()
+                .cert_path(cert_path)
+                .key_path(key_path)
+                .try_bind_with_graceful_shutdown(bind_address, shutdown_signal())
+                .context("Binding TLS API address failed")?;
+            (true, (addr.to_string(), server.boxed()))
+        }
+        (_, None, None) if dual_stack => {
+            let listener = dual_stack_listener(bind_address)
+                .with_context(|| format!("Binding dual stack address {} failed", bind_address))?;
+            let addr = listener.local_addr()?;
+            let server = server.serve_incoming_with_graceful_shutdown(
+                TcpListenerStream::new(listener),
+                shutdown_signal(),
+            );
+            (false, (addr.to_string(), server.boxed()))
+        }
+        (_, None, None) => {
+            let (addr, server) = server
+                .try_bind_with_graceful_shutdown(bind_address, shutdown_signal())
+                .context("Binding API address failed")?;
+            (false, (addr.to_string(), server.boxed()))
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Both tls_cert_path and tls_key_path have to be set to use TLS"
+            ))
+        }
+    };
+    {
+        let controller = served[0].controller.read().await;
+        let state = controller.get_state()?;
+        log::info!(
+            "{}",
+            serde_json::json!({
+                "event": "started",
+                "prefix": controller.get_prefix().to_str(),
+                "witnesses": state
+                    .iter()
+                    .flat_map(|state| state.witness_config.witnesses.iter().map(|w| w.to_str()))
+                    .collect::<Vec<_>>(),
+                "witness_threshold": state.as_ref().map(|state| &state.witness_config.tally),
+                "identifiers": served.iter().map(|served| &served.prefix).collect::<Vec<_>>(),
+                "listen_address": addr,
+                "tls": tls,
+                "read_only": read_only,
+            })
+        );
+    }
+    server.await;
 
-    warp::serve(routes)
-        .run((api_host.parse::<IpAddr>()?, api_port))
+    // In-flight requests are completed at this point, make sure nothing
+    // written by them is lost.
+    log::info!("Shutting down");
+    if let Some(path) = &unix_socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+    for served in served {
+        served.attest_db.read().await.flush()?;
+        // Routes are dropped with the server, so the controller is only
+        // still shared if a background task holds it.
+        match Arc::try_unwrap(served.controller) {
+            Ok(controller) => controller.into_inner().close()?,
+            Err(controller) => {
+                log::warn!(
+                    "Controller of {} is still in use, its key event database is flushed \
+                     by sled in the background",
+                    served.prefix
+                );
+                controller.read().await.flush()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens controller keys and database and loads the identifier stored there.
+/// With `incept`, the identifier is incepted if it's the first start,
+/// otherwise a missing one is an error. Keys are derived from `key_seed` if
+/// it's given, loaded from the key store or generated otherwise. A read-only
+/// instance opens only the database.
+async fn init_controller(
+    kel_db_path: &Path,
+    key_store_path: Option<PathBuf>,
+    key_seed: Option<String>,
+    bootstrap: BootstrapConfig,
+    read_only: bool,
+    incept: bool,
+    network_config: NetworkConfig,
+) -> Result<Controller> {
+    if read_only {
+        return Controller::open_read_only(
+            kel_db_path,
+            bootstrap.known_resolvers.unwrap_or_default(),
+            network_config,
+        );
+    }
+
+    let initial_threshold = bootstrap.witness_threshold.to_threshold()?;
+    validate_threshold(
+        bootstrap.witnesses.as_ref().map(Vec::len).unwrap_or(0),
+        &initial_threshold,
+    )
+    .context("Bootstrap witness threshold is invalid")?;
+
+    let key_count = bootstrap.key_count.unwrap_or(1);
+    let next_key_count = bootstrap.next_key_count.unwrap_or(1);
+    let signing_threshold = bootstrap
+        .signing_threshold
+        .as_ref()
+        .map(ThresholdConfig::to_threshold)
+        .transpose()?;
+    if let Some(signing_threshold) = &signing_threshold {
+        validate_key_threshold(key_count, signing_threshold)
+            .and_then(|_| validate_key_threshold(next_key_count, signing_threshold))
+            .context("Bootstrap signing threshold is invalid")?;
+    }
+
+    let key_store = match (key_seed, key_store_path) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "{} can't be used together with key_store_path",
+                KEY_SEED_ENV
+            ))
+        }
+        (Some(seed), None) => KeyStore::from_seed(&seed, key_count, next_key_count)
+            .context("Deriving keys from seed failed")?,
+        (None, Some(path)) => {
+            let passphrase = std::env::var(KEY_PASSPHRASE_ENV).with_context(|| {
+                format!("{} has to be set to use key store", KEY_PASSPHRASE_ENV)
+            })?;
+            KeyStore::open(&path, passphrase, key_count, next_key_count)
+                .context("Opening key store failed")?
+        }
+        (None, None) => KeyStore::generate(key_count, next_key_count),
+    };
+
+    let delegator = bootstrap.delegator.map(|delegator| DelegatorConfig {
+        location: base_url(delegator.location),
+        ..delegator
+    });
+    if !incept {
+        return Controller::open(
+            kel_db_path,
+            bootstrap.known_resolvers.unwrap_or_default(),
+            delegator,
+            key_store,
+            network_config,
+        )
         .await;
+    }
+    Controller::init(
+        kel_db_path,
+        bootstrap.known_resolvers.unwrap_or_default(),
+        InceptionConfig {
+            witnesses: bootstrap.witnesses,
+            witness_threshold: Some(initial_threshold),
+            signing_threshold,
+        },
+        delegator,
+        key_store,
+        network_config,
+    )
+    .await
+}
+
+/// Opens attestation store of the controller's identifier.
+fn serve_identifier(
+    controller: Controller,
+    attestation_db_path: PathBuf,
+    max_attestations: Option<usize>,
+) -> Result<ServedIdentifier> {
+    let attest_store = AttestationStore::open(&attestation_db_path, max_attestations)
+        .context("Attestation store init failed")?;
+    Ok(ServedIdentifier {
+        prefix: controller.get_prefix().to_str(),
+        controller: Arc::new(RwLock::new(controller)),
+        attest_db: Arc::new(RwLock::new(attest_store)),
+    })
+}
 
+/// Prints reachability report of configured witnesses and resolvers. The
+/// controller database is a temporary one, so a running daemon isn't
+/// disturbed and no identifier is incepted.
+async fn doctor(
+    witnesses: Vec<WitnessConfig>,
+    resolvers: Vec<Url>,
+    network_config: NetworkConfig,
+) -> Result<()> {
+    let db_path = std::env::temp_dir().join(format!("acdcd-doctor-{}", std::process::id()));
+    let cont = Controller::new(&db_path, resolvers, KeyStore::ephemeral(), network_config)?;
+
+    let mut failures = 0;
+    for resolver in cont.probe_resolvers().await {
+        match resolver {
+            (url, Ok(())) => println!("ok    resolver {}", url),
+            (url, Err(e)) => {
+                failures += 1;
+                println!("FAIL  resolver {}: {:#}", url, e);
+            }
+        }
+    }
+    for (index, witness) in witnesses.iter().enumerate() {
+        match cont.probe_witness(witness).await {
+            Ok((aid, location)) => println!("ok    witness {} at {}", aid.to_str(), location),
+            Err(e) => {
+                failures += 1;
+                println!("FAIL  witness #{}: {:#}", index, e);
+            }
+        }
+    }
+    drop(cont);
+    let _ = std::fs::remove_dir_all(&db_path);
+
+    if failures > 0 {
+        return Err(anyhow::anyhow!("{} check(s) failed", failures));
+    }
     Ok(())
 }
+
+/// Binds IPv6 socket which accepts IPv4 connections too.
+fn dual_stack_listener(address: SocketAddr) -> Result<TcpListener> {
+    if address.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return Err(anyhow::anyhow!("dual_stack needs api_host to be \"::\""));
+    }
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Binds unix socket at `path`, replacing a socket left there by a previous
+/// run. Other files aren't touched.
+fn unix_listener(path: &Path) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        _ => (),
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Resolves when the daemon is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Can't listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same minimal config in every supported format.
+    const CONFIGS: &[(&str, &str)] = &[
+        (
+            "config.json",
+            r#"{"kel_db_path": "kel", "api_host": "127.0.0.1", "api_port": 3030, "bootstrap": {"witness_threshold": 1}}"#,
+        ),
+        (
+            "config.toml",
+            "kel_db_path = \"kel\"\napi_host = \"127.0.0.1\"\napi_port = 3030\n\n[bootstrap]\nwitness_threshold = 1\n",
+        ),
+        (
+            "config.yaml",
+            "kel_db_path: kel\napi_host: 127.0.0.1\napi_port: 3030\nbootstrap:\n  witness_threshold: 1\n",
+        ),
+    ];
+
+    #[test]
+    fn config_is_read_in_every_format() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for (name, content) in CONFIGS {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+
+            let config: Config = config_figment(&path.to_string_lossy())
+                .extract()
+                .with_context(|| format!("Reading {} failed", name))?;
+
+            assert_eq!(config.kel_db_path, PathBuf::from("kel"), "{}", name);
+            assert_eq!(config.api_host, "127.0.0.1", "{}", name);
+        }
+        Ok(())
+    }
+
+    // The only test that sets `ACDCD_` variables, the other ones don't
+    // check the values they override.
+    #[test]
+    fn environment_overrides_config_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(CONFIGS[0].0);
+        std::fs::write(&path, CONFIGS[0].1)?;
+
+        std::env::set_var("ACDCD_API_PORT", "8080");
+        std::env::set_var("ACDCD_BOOTSTRAP__WITNESS_THRESHOLD", "2");
+        let config = config_figment(&path.to_string_lossy()).extract::<Config>();
+        std::env::remove_var("ACDCD_API_PORT");
+        std::env::remove_var("ACDCD_BOOTSTRAP__WITNESS_THRESHOLD");
+        let config = config?;
+
+        assert_eq!(config.api_port, 8080);
+        assert!(matches!(
+            config.bootstrap.witness_threshold,
+            ThresholdConfig::Simple(2)
+        ));
+        Ok(())
+    }
+}
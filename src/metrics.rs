@@ -0,0 +1,122 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of resolver lookup latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Daemon counters exposed in Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub attestations_created: AtomicU64,
+    pub attestations_received: AtomicU64,
+    pub attestations_rejected: AtomicU64,
+    pub witness_publish_successes: AtomicU64,
+    pub witness_publish_failures: AtomicU64,
+    resolver_latency: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_resolver_latency(&self, latency: Duration) {
+        let histogram = &self.resolver_latency;
+        let seconds = latency.as_secs_f64();
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        histogram.count.fetch_add(1, Ordering::Relaxed);
+        histogram
+            .sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics in Prometheus text exposition format. Sequence
+    /// number of the last KEL event is taken from the caller, because it's
+    /// kept in the controller database.
+    pub fn render(&self, kel_sn: Option<u64>) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "acdcd_attestations_created_total",
+                "Attestations created and signed by the daemon.",
+                &self.attestations_created,
+            ),
+            (
+                "acdcd_attestations_received_total",
+                "Received attestations that passed verification.",
+                &self.attestations_received,
+            ),
+            (
+                "acdcd_attestations_rejected_total",
+                "Received attestations that failed verification.",
+                &self.attestations_rejected,
+            ),
+            (
+                "acdcd_witness_publish_successes_total",
+                "Events receipted by a witness.",
+                &self.witness_publish_successes,
+            ),
+            (
+                "acdcd_witness_publish_failures_total",
+                "Events a witness failed to receipt.",
+                &self.witness_publish_failures,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let name = "acdcd_resolver_lookup_seconds";
+        let histogram = &self.resolver_latency;
+        let _ = writeln!(out, "# HELP {} Latency of resolver lookups.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        if let Some(sn) = kel_sn {
+            let name = "acdcd_kel_sequence_number";
+            let _ = writeln!(
+                out,
+                "# HELP {} Sequence number of the last KEL event.",
+                name
+            );
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, sn);
+        }
+        out
+    }
+}
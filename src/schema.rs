@@ -0,0 +1,36 @@
+use anyhow::Result;
+use jsonschema::JSONSchema;
+use keri::prefix::SelfAddressingPrefix;
+use serde_json::Value;
+
+/// Checks that schema is self-addressing: its `$id` is a digest of the schema
+/// with `$id` replaced by the same number of `#` characters. Returns the
+/// schema SAID.
+pub fn verify_said(schema: &str) -> Result<String> {
+    let parsed: Value = serde_json::from_str(schema)?;
+    let said = parsed
+        .get("$id")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow::anyhow!("Schema has no $id"))?;
+    let digest: SelfAddressingPrefix = said.parse()?;
+
+    let placeholder = "#".repeat(said.len());
+    let dummy_schema = schema.replacen(said, &placeholder, 1);
+    if digest.verify_binding(dummy_schema.as_bytes()) {
+        Ok(said.to_string())
+    } else {
+        Err(anyhow::anyhow!(
+            "Schema $id {} doesn't match its content",
+            said
+        ))
+    }
+}
+
+/// Validates attestation attributes against JSON schema. Returns
+/// descriptions of all violations.
+pub fn validate(schema: &Value, attributes: &Value) -> Result<(), Vec<String>> {
+    let schema = JSONSchema::compile(schema).map_err(|e| vec![e.to_string()])?;
+    schema
+        .validate(attributes)
+        .map_err(|errors| errors.map(|e| e.to_string()).collect())
+}
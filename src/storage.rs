@@ -0,0 +1,328 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+};
+
+use acdc::{Attestation, Hashed, Signed};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::controller::open_sled;
+
+pub type SignedAttestation = Signed<Hashed<Attestation>>;
+
+/// Returns identifier the attestation was issued to, the `i` field of its
+/// attributes.
+pub fn recipient(attest: &SignedAttestation) -> Option<String> {
+    serde_json::to_value(&attest.data)
+        .ok()?
+        .get("a")?
+        .get("i")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Returns issuance datetime the attestation carries in the `dt` field of
+/// its attributes, if any.
+pub fn issued_at<A: Serialize>(attest: &A) -> Option<String> {
+    serde_json::to_value(attest)
+        .ok()?
+        .get("a")?
+        .get("dt")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Returns hashes of prior attestations the attestation chains to, the `p`
+/// (provenance) field. Edges are either plain hashes or objects with the
+/// hash in `d`.
+pub fn edges<A: Serialize>(attest: &A) -> Vec<String> {
+    serde_json::to_value(attest)
+        .ok()
+        .and_then(|attest| attest.get("p")?.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|edge| {
+            edge.as_str()
+                .or_else(|| edge.get("d")?.as_str())
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// Attestation store written through to a sled database, so attestations
+/// survive daemon restarts. Attestations are stored on disk in their signed
+/// JSON form, the same one that is exchanged over the API, so the stored bytes
+/// stay verifiable. Recently inserted attestations are also kept in memory, up
+/// to the configured limit, older ones are read from disk when needed.
+pub struct AttestationStore {
+    /// Cached attestations, every one of them is on disk too.
+    attestations: HashMap<String, SignedAttestation>,
+    /// Hashes of cached attestations in insertion order, the oldest ones are
+    /// evicted first.
+    cache_order: VecDeque<String>,
+    /// Most attestations kept in memory, unlimited if not set.
+    cache_limit: Option<usize>,
+    db: sled::Db,
+    /// Registered attestation schemas, keyed by schema SAID.
+    schemas: sled::Tree,
+    /// When attestations were first stored, RFC 3339 datetimes keyed by
+    /// attestation hash.
+    stored_at: sled::Tree,
+    /// Hashes of attestations created by requests with an idempotency key,
+    /// keyed by the key.
+    idempotency_keys: sled::Tree,
+    /// Newly stored attestations, in signed JSON form.
+    stored: broadcast::Sender<String>,
+}
+
+/// How many stored attestations a slow subscriber can lag behind.
+const SUBSCRIPTION_CAPACITY: usize = 100;
+
+impl AttestationStore {
+    pub fn open(db_path: &Path, cache_limit: Option<usize>) -> Result<Self> {
+        let db = open_sled(db_path).context("Opening attestation database failed")?;
+        let schemas = db
+            .open_tree("schemas")
+            .context("Opening schema database failed")?;
+        let stored_at = db
+            .open_tree("stored_at")
+            .context("Opening attestation timestamp database failed")?;
+        let idempotency_keys = db
+            .open_tree("idempotency_keys")
+            .context("Opening idempotency key database failed")?;
+
+        let mut store = AttestationStore {
+            attestations: HashMap::new(),
+            cache_order: VecDeque::new(),
+            cache_limit,
+            db,
+            schemas,
+            stored_at,
+            idempotency_keys,
+            stored: broadcast::channel(SUBSCRIPTION_CAPACITY).0,
+        };
+        let cached = store
+            .db
+            .iter()
+            .take(cache_limit.unwrap_or(usize::MAX))
+            .map(|entry| -> Result<_> {
+                let (hash, attest) = entry?;
+                let hash = String::from_utf8(hash.to_vec())?;
+                let attest = parse_stored(&hash, &attest)?;
+                Ok((hash, attest))
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("Loading stored attestations failed")?;
+        for (hash, attest) in cached {
+            store.cache(hash, attest);
+        }
+        Ok(store)
+    }
+
+    pub fn insert(&mut self, hash: String, attest: SignedAttestation) -> Result<()> {
+        let signed_json = attest.to_signed_json();
+        self.db
+            .insert(hash.as_bytes(), signed_json.as_bytes())
+            .context("Saving attestation failed")?;
+        // Storing the same attestation again keeps the first timestamp.
+        if !self.stored_at.contains_key(hash.as_bytes())? {
+            self.stored_at
+                .insert(hash.as_bytes(), Utc::now().to_rfc3339().as_bytes())
+                .context("Saving attestation timestamp failed")?;
+        }
+        // Nobody listening isn't an error.
+        let _ = self.stored.send(signed_json);
+        self.cache(hash, attest);
+        Ok(())
+    }
+
+    /// Keeps attestation in memory, evicting the oldest cached ones above
+    /// the limit. Evicted attestations stay on disk.
+    fn cache(&mut self, hash: String, attest: SignedAttestation) {
+        if self.attestations.insert(hash.clone(), attest).is_none() {
+            self.cache_order.push_back(hash);
+        }
+        while self.attestations.len() > self.cache_limit.unwrap_or(usize::MAX) {
+            match self.cache_order.pop_front() {
+                Some(evicted) => {
+                    log::debug!("Evicted attestation {:?} from memory", evicted);
+                    self.attestations.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Removes attestation from memory and disk. Returns removed attestation,
+    /// if it was stored.
+    pub fn remove(&mut self, hash: &str) -> Result<Option<SignedAttestation>> {
+        let removed = self.get(hash);
+        self.db
+            .remove(hash.as_bytes())
+            .context("Removing attestation failed")?;
+        self.stored_at.remove(hash.as_bytes())?;
+        if self.attestations.remove(hash).is_some() {
+            self.cache_order.retain(|cached| cached != hash);
+        }
+        Ok(removed)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<SignedAttestation> {
+        if let Some(attest) = self.attestations.get(hash) {
+            return Some(attest.clone());
+        }
+        let stored = match self.db.get(hash.as_bytes()) {
+            Ok(stored) => stored?,
+            Err(e) => {
+                log::warn!("Reading attestation {:?} failed: {}", hash, e);
+                return None;
+            }
+        };
+        parse_stored(hash, &stored)
+            .map_err(|e| log::warn!("{:#}", e))
+            .ok()
+    }
+
+    /// Returns when attestation was first stored. Attestations stored before
+    /// timestamps were recorded have none.
+    pub fn stored_at(&self, hash: &str) -> Option<DateTime<Utc>> {
+        let stored_at = self
+            .stored_at
+            .get(hash.as_bytes())
+            .map_err(|e| log::warn!("Reading attestation timestamp failed: {}", e))
+            .ok()??;
+        DateTime::parse_from_rfc3339(std::str::from_utf8(&stored_at).ok()?)
+            .map(|stored_at| stored_at.with_timezone(&Utc))
+            .ok()
+    }
+
+    pub fn contains(&self, hash: &str) -> Result<bool> {
+        Ok(self.attestations.contains_key(hash)
+            || self
+                .db
+                .contains_key(hash.as_bytes())
+                .context("Reading attestation failed")?)
+    }
+
+    pub fn insert_schema(&self, said: &str, schema: &str) -> Result<()> {
+        self.schemas
+            .insert(said.as_bytes(), schema.as_bytes())
+            .context("Saving schema failed")?;
+        Ok(())
+    }
+
+    pub fn get_schema(&self, said: &str) -> Result<Option<serde_json::Value>> {
+        self.schemas
+            .get(said.as_bytes())?
+            .map(|schema| Ok(serde_json::from_slice(&schema)?))
+            .transpose()
+    }
+
+    /// Returns hash of the attestation created for idempotency `key`.
+    pub fn get_idempotent(&self, key: &str) -> Result<Option<String>> {
+        self.idempotency_keys
+            .get(key.as_bytes())?
+            .map(|hash| Ok(String::from_utf8(hash.to_vec())?))
+            .transpose()
+    }
+
+    pub fn insert_idempotent(&self, key: &str, hash: &str) -> Result<()> {
+        self.idempotency_keys
+            .insert(key.as_bytes(), hash.as_bytes())
+            .context("Saving idempotency key failed")?;
+        Ok(())
+    }
+
+    /// Writes all pending changes to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db
+            .flush()
+            .context("Flushing attestation database failed")?;
+        Ok(())
+    }
+
+    /// Returns receiver of attestations stored from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.stored.subscribe()
+    }
+
+    /// Iterates over every stored attestation, including the ones not kept
+    /// in memory. Malformed ones are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (String, SignedAttestation)> + '_ {
+        self.db.iter().filter_map(move |entry| {
+            let (hash, stored) = entry
+                .map_err(|e| log::warn!("Reading attestations failed: {}", e))
+                .ok()?;
+            let hash = String::from_utf8(hash.to_vec()).ok()?;
+            let attest = match self.attestations.get(&hash) {
+                Some(attest) => attest.clone(),
+                None => parse_stored(&hash, &stored)
+                    .map_err(|e| log::warn!("{:#}", e))
+                    .ok()?,
+            };
+            Some((hash, attest))
+        })
+    }
+}
+
+fn parse_stored(hash: &str, stored: &[u8]) -> Result<SignedAttestation> {
+    Signed::from_signed_json(std::str::from_utf8(stored)?)
+        .map_err(|_| anyhow::anyhow!("Stored attestation {} is malformed", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use keri::{
+        derivation::self_signing::SelfSigning, prefix::AttachedSignaturePrefix, signer::KeyManager,
+    };
+
+    use super::*;
+    use crate::keys::KeyStore;
+
+    fn signed_attestation(keys: &KeyStore, name: &str) -> Result<SignedAttestation> {
+        let attest = Hashed::new(serde_json::from_value::<Attestation>(serde_json::json!({
+            "a": {"name": name},
+            "i": "issuer",
+            "p": [],
+            "r": [],
+            "s": "E46jrVPTzlSkUPqGGeIZ8a8FWS7a6s4reAXRZOkogZ2A",
+            "v": "ACDC10JSON00011c_",
+        }))?);
+        let signature = keys.sign(&Signed::get_json_bytes(&attest))?;
+        Signed::new_with_keri_signatures(
+            attest,
+            &[AttachedSignaturePrefix::new(
+                SelfSigning::Ed25519Sha512,
+                signature,
+                0,
+            )],
+        )
+        .map_err(|_| anyhow::anyhow!("Signing attestation failed"))
+    }
+
+    #[test]
+    fn attestations_above_cache_limit_are_evicted_to_disk() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let keys = KeyStore::ephemeral();
+        let mut store = AttestationStore::open(dir.path(), Some(2))?;
+        let attests = ["John", "Jane", "Jim"]
+            .iter()
+            .map(|name| signed_attestation(&keys, name))
+            .collect::<Result<Vec<_>>>()?;
+        for attest in &attests {
+            store.insert(attest.data.get_hash().to_string(), attest.clone())?;
+        }
+
+        let oldest = attests[0].data.get_hash().to_string();
+        assert_eq!(store.attestations.len(), 2);
+        assert!(!store.attestations.contains_key(&oldest));
+        assert_eq!(
+            store.get(&oldest).map(|attest| attest.to_signed_json()),
+            Some(attests[0].to_signed_json())
+        );
+        Ok(())
+    }
+}
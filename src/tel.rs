@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use keri::{
+    derivation::self_addressing::SelfAddressing,
+    prefix::{Prefix, SelfAddressingPrefix},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::open_sled;
+
+/// Status of an attestation issued by the controller.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttestationStatus {
+    Issued,
+    Revoked,
+}
+
+/// Revocation event of the controller's attestation registry. Issuance is
+/// implicit, an attestation is issued until its revocation is anchored in the
+/// KEL.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TelEvent {
+    #[serde(rename = "t")]
+    pub event_type: String,
+    /// Hash of the revoked attestation.
+    #[serde(rename = "i")]
+    pub attestation: String,
+    #[serde(rename = "s")]
+    pub sn: String,
+    /// Digest of the event, anchored in the KEL.
+    #[serde(rename = "d")]
+    pub digest: String,
+}
+
+impl TelEvent {
+    pub fn revocation(attestation: &str) -> Result<(Self, SelfAddressingPrefix)> {
+        let mut event = TelEvent {
+            event_type: "rev".into(),
+            attestation: attestation.into(),
+            sn: "1".into(),
+            digest: String::new(),
+        };
+        let digest = SelfAddressing::Blake3_256.derive(&serde_json::to_vec(&event)?);
+        event.digest = digest.to_str();
+        Ok((event, digest))
+    }
+}
+
+/// Transaction event log of attestations issued by the controller.
+pub struct Tel {
+    db: sled::Db,
+}
+
+impl Tel {
+    pub fn open(db_path: &Path) -> Result<Self> {
+        let db = open_sled(db_path).context("Opening TEL database failed")?;
+        Ok(Tel { db })
+    }
+
+    pub fn insert(&self, event: &TelEvent) -> Result<()> {
+        self.db
+            .insert(event.attestation.as_bytes(), serde_json::to_vec(event)?)
+            .context("Saving TEL event failed")?;
+        Ok(())
+    }
+
+    pub fn get(&self, attestation: &str) -> Result<Option<TelEvent>> {
+        self.db
+            .get(attestation.as_bytes())?
+            .map(|event| Ok(serde_json::from_slice(&event)?))
+            .transpose()
+    }
+
+    pub fn status(&self, attestation: &str) -> Result<AttestationStatus> {
+        Ok(match self.get(attestation)? {
+            Some(_) => AttestationStatus::Revoked,
+            None => AttestationStatus::Issued,
+        })
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush().context("Flushing TEL database failed")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{controller::NetworkConfig, Url};
+
+/// Witness answer to a published event.
+pub struct PublishResponse {
+    pub receipts: Vec<String>,
+    /// Problems the witness found with the event, like a bad signature.
+    pub errors: Vec<String>,
+}
+
+/// Communication with witnesses. Witness implementations differ in how events
+/// are delivered to them and how receipts are returned, so the controller
+/// doesn't depend on any particular protocol.
+#[async_trait]
+pub trait WitnessTransport: Send + Sync {
+    /// Sends signed event to witness at `location` and returns receipts the
+    /// witness produced for it, along with errors it reported.
+    async fn publish(&self, location: &Url, event: &[u8]) -> Result<PublishResponse>;
+
+    /// Delivers key event log or receipts to witness at `location`.
+    async fn send(&self, location: &Url, stream: &[u8]) -> Result<()>;
+}
+
+/// Witness protocol of [keri-witness-http](https://github.com/THCLab/keri-witness-http):
+/// streams are posted to `{location}publish` and receipts are returned in
+/// the response body.
+pub struct HttpTransport {
+    client: Client,
+    network_config: NetworkConfig,
+}
+
+impl HttpTransport {
+    pub fn new(client: Client, network_config: NetworkConfig) -> Self {
+        HttpTransport {
+            client,
+            network_config,
+        }
+    }
+
+    /// Posts `body` to witness publish endpoint. Failed requests are retried
+    /// with exponential backoff.
+    async fn post(&self, location: &Url, body: &[u8]) -> Result<reqwest::Response> {
+        let endpoint = location.join("publish")?;
+        let mut backoff = self.network_config.publish_backoff;
+        let mut retries = 0;
+        loop {
+            match self
+                .client
+                .post(endpoint.clone())
+                .body(body.to_vec())
+                .send()
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(e) if retries < self.network_config.publish_retries => {
+                    log::warn!("Sending to witness {} failed, retrying: {}", location, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    retries += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl WitnessTransport for HttpTransport {
+    async fn publish(&self, location: &Url, event: &[u8]) -> Result<PublishResponse> {
+        /// Helper struct for deserializing data provided by witnesses
+        #[derive(Serialize, Deserialize)]
+        struct RespondData {
+            parsed: u64,
+            not_parsed: String,
+            receipts: Vec<String>,
+            errors: Vec<String>,
+        }
+
+        let body = self
+            .post(location, event)
+            .await
+            .with_context(|| format!("Publishing event to witness {} failed", location))?
+            .text()
+            .await
+            .with_context(|| format!("Reading response of witness {} failed", location))?;
+        log::debug!("Witness {} answered: {}", location, body);
+        let response = serde_json::from_str::<RespondData>(&body)
+            .with_context(|| format!("Unexpected response of witness {}: {}", location, body))?;
+        Ok(PublishResponse {
+            receipts: response.receipts,
+            errors: response.errors,
+        })
+    }
+
+    async fn send(&self, location: &Url, stream: &[u8]) -> Result<()> {
+        log::debug!(
+            "Sending to witness {}: {}",
+            location,
+            String::from_utf8_lossy(stream)
+        );
+        self.post(location, stream).await?;
+        Ok(())
+    }
+}
+
+/// Witnesses which don't return receipts in the publish response, like
+/// keripy ones. Streams are posted the same way as with `HttpTransport`, then
+/// `{location}{mailbox_path}?pre={prefix}&sn={sn}` is polled until it returns
+/// the receipt of the event. The mailbox answers `404 not found` or an empty
+/// body while there's no receipt yet.
+pub struct MailboxTransport {
+    http: HttpTransport,
+    mailbox_path: String,
+}
+
+impl MailboxTransport {
+    pub fn new(client: Client, network_config: NetworkConfig, mailbox_path: String) -> Self {
+        MailboxTransport {
+            http: HttpTransport::new(client, network_config),
+            mailbox_path,
+        }
+    }
+}
+
+#[async_trait]
+impl WitnessTransport for MailboxTransport {
+    /// Polls until the receipt shows up, the caller bounds how long it waits.
+    async fn publish(&self, location: &Url, event: &[u8]) -> Result<PublishResponse> {
+        self.http
+            .post(location, event)
+            .await?
+            .error_for_status()
+            .with_context(|| format!("Publishing event to witness {} failed", location))?;
+
+        let (prefix, sn) = event_id(event)?;
+        let mut mailbox = location.join(&self.mailbox_path)?;
+        mailbox
+            .query_pairs_mut()
+            .append_pair("pre", &prefix)
+            .append_pair("sn", &sn);
+        loop {
+            let response = self
+                .http
+                .client
+                .get(mailbox.clone())
+                .send()
+                .await
+                .with_context(|| format!("Polling mailbox of witness {} failed", location))?;
+            match response.status() {
+                status if status.is_success() => {
+                    let body = response.text().await.with_context(|| {
+                        format!("Reading mailbox of witness {} failed", location)
+                    })?;
+                    log::debug!("Mailbox of witness {} answered: {}", location, body);
+                    if !body.trim().is_empty() {
+                        return Ok(PublishResponse {
+                            receipts: vec![body],
+                            errors: vec![],
+                        });
+                    }
+                }
+                StatusCode::NOT_FOUND => (),
+                status => {
+                    return Err(anyhow::anyhow!(
+                        "Mailbox of witness {} answered {}",
+                        location,
+                        status
+                    ))
+                }
+            }
+            tokio::time::sleep(self.http.network_config.mailbox_poll_interval).await;
+        }
+    }
+
+    async fn send(&self, location: &Url, stream: &[u8]) -> Result<()> {
+        self.http.send(location, stream).await
+    }
+}
+
+/// Returns identifier and sequence number of the event the stream starts
+/// with, as they're written in the event.
+fn event_id(stream: &[u8]) -> Result<(String, String)> {
+    let event = serde_json::Deserializer::from_slice(stream)
+        .into_iter::<serde_json::Value>()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Published stream holds no event"))?
+        .context("Published event is malformed")?;
+    let field = |name: &str| {
+        event
+            .get(name)
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Published event has no {:?} field", name))
+    };
+    Ok((field("i")?, field("s")?))
+}
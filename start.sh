@@ -42,7 +42,7 @@ sleep 1
 
 print_step "Creating attestation..."
 curl http://localhost:13434/attestations/create -H 'Content-Type:application/json' \
-    -d '{"v":"ACDC10JSON00011c_","i":"alice","s":"E46jrVPTzlSkUPqGGeIZ8a8FWS7a6s4reAXRZOkogZ2A","a":{},"p":[],"r":[]}' \
+    -d '{"v":"ACDC10JSON00011c_","i":"alice","s":"E46jrVPTzlSkUPqGGeIZ8a8FWS7a6s4reAXRZOkogZ2A","a":{"name":"alice"},"p":[],"r":[]}' \
     >/tmp/attest.txt
 sleep 1
 